@@ -0,0 +1,33 @@
+dofile("programs/rainbow_cube.lua")
+
+-- A static field of cubes, spawned once and drawn without rebuilding the draw list each frame.
+-- Only the spinning cube in the middle is touched per frame.
+function reload()
+    if init == nil then
+        local cube = rainbow_cube()
+        mesh = add_mesh(cube[1], cube[2])
+        shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "tri")
+
+        for x = -10, 10 do
+            for z = -10, 10 do
+                if x ~= 0 or z ~= 0 then
+                    spawn {
+                        trans = mat4.translation(vec3(x * 4, -4, z * 4)),
+                        mesh = mesh,
+                        shader = shader,
+                    }
+                end
+            end
+        end
+
+        spinner = spawn { mesh = mesh, shader = shader }
+        init = true
+    end
+end
+
+function frame(ctx)
+    update_object(spinner, {
+        trans = mat4.rotation(vec3(0, 1, 0), ctx.time),
+    })
+    return { draw = {} }
+end
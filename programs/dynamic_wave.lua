@@ -0,0 +1,41 @@
+-- A grid of points which is rewritten every frame
+local size = 64
+
+function wave_grid(t)
+    local vertices = {}
+    local indices = {}
+    for i = 0, size - 1 do
+        for j = 0, size - 1 do
+            local x = (i / size - 0.5) * 10.
+            local z = (j / size - 0.5) * 10.
+            local y = math.sin(x + t) * math.cos(z + t * 0.7) * 0.5
+            table.insert(vertices, x)
+            table.insert(vertices, y)
+            table.insert(vertices, z)
+            table.insert(vertices, i / size)
+            table.insert(vertices, (y + 0.5))
+            table.insert(vertices, j / size)
+            table.insert(indices, i * size + j)
+        end
+    end
+    return { vertices, indices }
+end
+
+function reload()
+    if init == nil then
+        local grid = wave_grid(0)
+        mesh = add_mesh(grid[1], grid[2], { dynamic = true })
+        shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "points")
+        init = true
+    end
+end
+
+function frame(ctx)
+    local grid = wave_grid(ctx.time)
+    update_mesh(mesh, grid[1], grid[2])
+    return {
+        draw = {
+            { mesh = mesh, shader = shader },
+        },
+    }
+end
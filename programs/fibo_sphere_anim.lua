@@ -1,9 +1,7 @@
-dofile("programs/lin_alg.lua")
 dofile("programs/rainbow_cube.lua")
 
 function reload()
     if init == nil then
-        anim = 0.0
         cube = rainbow_cube()
         mesh = add_mesh(cube[1], cube[2])
         shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "tri")
@@ -11,10 +9,10 @@ function reload()
     end
 end
 
-function frame()
-    anim = anim + 0.01
+function frame(ctx)
+    local anim = ctx.time * 0.6
 
-    objs = {}
+    local objs = {}
     golden = (1. + math.sqrt(5.)) / 2.
     n = math.floor(1000. * ((math.cos(anim / 2.) + 1.) / 2.))
     qn = n
@@ -34,13 +32,12 @@ function frame()
 
         if i <= qn then
             objs[i] = {
-                --cannon(translate(x, y, z)),
-                trans=cannon(gemm(translate(x, y, z), rot_y(theta))),
+                trans=mat4.translation(vec3(x, y, z)) * mat4.rotation(vec3(0, 1, 0), theta),
                 shader=shader,
                 mesh=mesh
             }
         end
     end
 
-    return objs
+    return { draw = objs }
 end
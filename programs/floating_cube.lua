@@ -1,28 +1,35 @@
-dofile("programs/lin_alg.lua")
 dofile("programs/rainbow_cube.lua")
 
 function reload()
     if init == nil then
-        anim = 0.0
         cube = rainbow_cube()
         mesh = add_mesh(cube[1], cube[2])
-        shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "tri")
+        shader = track_shader("shaders/instanced.vert", "shaders/unlit.frag", "tri")
         init = true
     end
 end
 
-function frame()
-    anim = anim + 0.01
-    objs = {anim=anim}
+local tint_layout = { { name = "tint", components = 3 } }
+
+function frame(ctx)
+    local anim = ctx.time * 0.6
+    local transforms = {}
+    local tints = {}
+    local up = vec3(0, 1, 0)
     for i = 1, 1000 do
-        objs[i] = {
-            trans=cannon(gemm(
-                translate(0, i, 0),
-                rot_y(anim + i)
-            )),
+        transforms[i] = mat4.translation(vec3(0, i, 0)) * mat4.rotation(up, anim + i)
+        local fade = 1 - i / 1000
+        tints[#tints + 1] = 1
+        tints[#tints + 1] = fade
+        tints[#tints + 1] = fade
+    end
+    return { draw = {
+        {
+            instances=transforms,
+            instance_data=tints,
+            instance_layout=tint_layout,
             mesh=mesh,
             shader=shader,
         }
-    end
-    return objs
+    } }
 end
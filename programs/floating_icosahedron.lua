@@ -3,7 +3,6 @@ dofile("programs/icosahedron.lua")
 
 function reload()
     if init == nil then
-        anim = 0.0
         cube = icosahedron(3.0, "tris")
         mesh = add_mesh(cube[1], cube[2])
         shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "triangles")
@@ -11,9 +10,9 @@ function reload()
     end
 end
 
-function frame()
-    anim = anim + 0.03
-    local objs = {anim=anim}
+function frame(ctx)
+    local anim = ctx.time * 1.8
+    local objs = {}
     objs[1] = {
         trans=cannon(gemm(
             translate(0, math.cos(anim) / 5., 0),
@@ -22,5 +21,5 @@ function frame()
         mesh=mesh,
         shader=shader,
     }
-    return objs
+    return { draw = objs }
 end
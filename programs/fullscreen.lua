@@ -3,19 +3,21 @@ dofile("programs/rainbow_cube.lua")
 
 function reload()
     if init == nil then
-        anim = 0.0
         fullscreen = track_shader("shaders/fullscreen_tri.vert", "shaders/neat_pattern.frag", "tri")
         init = true
     end
 end
 
-function frame()
-    anim = anim + 1.0
+function frame(ctx)
     return {
-        anim=anim,
-        {
-            n_indices=3,
-            shader=fullscreen,
+        draw = {
+            {
+                n_indices=3,
+                shader=fullscreen,
+            },
+        },
+        params = {
+            anim = ctx.time * 60.0,
         },
     }
 end
@@ -0,0 +1,78 @@
+dofile("programs/rainbow_cube.lua")
+
+local vertex = [[
+#version 450
+#extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
+
+#include "common.glsl"
+
+layout(binding = 1) buffer Models {
+    mat4 model_mats[];
+};
+
+layout(push_constant) uniform Indices {
+    uint model_index;
+};
+
+layout(location = 0) in vec3 vert_pos;
+layout(location = 1) in vec3 vert_color;
+
+layout(location = 0) out vec3 frag_color;
+
+void main() {
+    gl_Position = camera[gl_ViewIndex]
+        * model_mats[model_index + gl_InstanceIndex]
+        * vec4(vert_pos, 1.0);
+    frag_color = vert_color;
+}
+]]
+
+-- Edit the colors and save; only this shader is recompiled
+local fragment = [[
+#version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "common.glsl"
+
+layout(location = 0) in vec3 frag_color;
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    float pulse = 0.5 + 0.5 * sin(time * 3.0);
+    out_color = vec4(mix(frag_color, vec3(1.0) - frag_color, pulse), 1.0);
+}
+]]
+
+-- A second shader() call; each call site gets its own shader
+local inverted = [[
+#version 450
+
+layout(location = 0) in vec3 frag_color;
+layout(location = 0) out vec4 out_color;
+
+void main() {
+    out_color = vec4(vec3(1.0) - frag_color, 1.0);
+}
+]]
+
+function reload()
+    if mesh == nil then
+        local cube = rainbow_cube()
+        mesh = add_mesh(cube[1], cube[2])
+    end
+    pulse_shader = shader(vertex, fragment, "tri")
+    inverted_shader = shader(vertex, inverted, "tri")
+end
+
+function frame(ctx)
+    local up = vec3(0, 1, 0)
+    return { draw = {
+        { mesh = mesh, shader = pulse_shader, trans = mat4.rotation(up, ctx.time) },
+        {
+            mesh = mesh,
+            shader = inverted_shader,
+            trans = mat4.translation(vec3(3, 0, 0)) * mat4.rotation(up, -ctx.time),
+        },
+    } }
+end
@@ -0,0 +1,43 @@
+-- Many polylines in a single line strip mesh, separated by the primitive restart index
+local n_lines = 32
+local n_points = 128
+
+function polylines(t)
+    local vertices = {}
+    local indices = {}
+    for line = 0, n_lines - 1 do
+        local phase = line / n_lines * math.pi * 2
+        for i = 0, n_points - 1 do
+            local x = (i / (n_points - 1) - 0.5) * 10.
+            local y = math.sin(x * 2 + phase + t) * 0.5 + line * 0.1
+            table.insert(vertices, x)
+            table.insert(vertices, y)
+            table.insert(vertices, 0)
+            table.insert(vertices, line / n_lines)
+            table.insert(vertices, 1 - line / n_lines)
+            table.insert(vertices, 1)
+            table.insert(indices, line * n_points + i)
+        end
+        table.insert(indices, PRIMITIVE_RESTART)
+    end
+    return { vertices, indices }
+end
+
+function reload()
+    if init == nil then
+        local lines = polylines(0)
+        mesh = add_mesh(lines[1], lines[2], { dynamic = true, primitive = "line_strip" })
+        shader = track_shader("shaders/unlit.vert", "shaders/unlit.frag", "line_strip", { restart = true })
+        init = true
+    end
+end
+
+function frame(ctx)
+    local lines = polylines(ctx.time)
+    update_mesh(mesh, lines[1], lines[2])
+    return {
+        draw = {
+            { mesh = mesh, shader = shader },
+        },
+    }
+end
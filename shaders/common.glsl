@@ -0,0 +1,13 @@
+// Shared declarations, included with `#include "common.glsl"`
+
+// Per-frame UBO
+layout(binding = 0) uniform PerFrame {
+    mat4 camera[2];
+    float anim;
+    float time;
+    float delta_time;
+    uint frame;
+    vec2 resolution;
+    vec2 mouse;
+    uvec3 midi;
+};
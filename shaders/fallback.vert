@@ -0,0 +1,26 @@
+#version 450
+#extension GL_EXT_multiview : require
+
+// Drawn in place of shaders which have never compiled. Built into the engine, so it doesn't
+// include common.glsl; only the start of the PerFrame block is declared.
+
+layout(binding = 0) uniform PerFrame {
+    mat4 camera[2];
+};
+
+layout(binding = 1) buffer Models {
+    mat4 model_mats[];
+};
+
+layout(push_constant) uniform Indices {
+    uint model_index;
+};
+
+layout(location = 0) in vec3 vert_pos;
+
+void main() {
+    gl_Position = camera[gl_ViewIndex]
+        * model_mats[model_index + gl_InstanceIndex]
+        * vec4(vert_pos, 1.0);
+    gl_PointSize = 4.0;
+}
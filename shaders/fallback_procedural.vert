@@ -0,0 +1,10 @@
+#version 450
+
+// Drawn in place of shaders which have never compiled, for draws without a mesh. These are
+// usually fullscreen effects, so it covers the screen with a single triangle.
+
+void main() {
+    vec2 uv = vec2(gl_VertexIndex & 2, (gl_VertexIndex << 1) & 2);
+    gl_Position = vec4(uv * 2.0 - 1.0, 0.0, 1.0);
+    gl_PointSize = 4.0;
+}
@@ -1,11 +1,8 @@
 #version 450
 #extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
 
-// Per-frame UBO
-layout(binding = 0) uniform PerFrame {
-    mat4 camera[2];
-    float anim;
-};
+#include "common.glsl"
 
 // Model matrices
 layout(binding = 1) buffer Models {
@@ -17,10 +14,6 @@ layout(push_constant) uniform Indices {
     uint model_index;
 };
 
-// Vertex data
-layout(location = 0) in vec3 vert_pos;
-layout(location = 1) in vec3 vert_color;
-
 // Fragment outputs
 layout(location = 0) out vec3 frag_color;
 
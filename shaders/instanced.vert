@@ -0,0 +1,33 @@
+#version 450
+#extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
+
+#include "common.glsl"
+
+// Model matrices
+layout(binding = 1) buffer Models {
+    mat4 model_mats[];
+};
+
+// Resource indices; instances' transforms follow the first one
+layout(push_constant) uniform Indices {
+    uint model_index;
+};
+
+// Vertex data
+layout(location = 0) in vec3 vert_pos;
+layout(location = 1) in vec3 vert_color;
+
+// Instance data
+layout(location = 2) in vec3 inst_tint;
+
+// Fragment outputs
+layout(location = 0) out vec3 frag_color;
+
+void main() {
+    gl_Position = camera[gl_ViewIndex]
+        * model_mats[model_index + gl_InstanceIndex]
+        * vec4(vert_pos, 1.0);
+    frag_color = vert_color * inst_tint;
+}
+
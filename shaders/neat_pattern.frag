@@ -1,11 +1,8 @@
 #version 450
 #extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
 
-// Per-frame UBO
-layout(binding = 0) uniform PerFrame {
-    mat4 camera[2];
-    float anim;
-};
+#include "common.glsl"
 
 layout(location = 0) in vec3 frag_color;
 layout(location = 0) out vec4 out_color;
@@ -64,7 +61,8 @@ vec3 shape(vec3 v) {
 }
 
 vec3 smpl(vec2 spos) {
-    vec2 st = norma(spos/1200.);
+    vec2 st = norma(spos / resolution);
+    st.x *= resolution.x / resolution.y;
 
     const float near = 1.0;
     const float far = 2.0;
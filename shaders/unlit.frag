@@ -1,11 +1,7 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
 
-// Per-frame UBO
-layout(binding = 0) uniform PerFrame {
-    mat4 camera[2];
-    uvec3 midi;
-    float anim;
-};
+#include "common.glsl"
 
 layout(location = 0) in vec3 frag_color;
 layout(location = 0) out vec4 out_color;
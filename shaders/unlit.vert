@@ -1,11 +1,8 @@
 #version 450
 #extension GL_EXT_multiview : require
+#extension GL_GOOGLE_include_directive : require
 
-// Per-frame UBO
-layout(binding = 0) uniform PerFrame {
-    mat4 camera[2];
-    float anim;
-};
+#include "common.glsl"
 
 // Model matrices
 layout(binding = 1) buffer Models {
@@ -26,7 +23,7 @@ layout(location = 0) out vec3 frag_color;
 
 void main() {
     gl_Position = camera[gl_ViewIndex]
-        * model_mats[model_index]
+        * model_mats[model_index + gl_InstanceIndex]
         * vec4(vert_pos, 1.0);
     frag_color = vert_color;
 }
@@ -8,6 +8,12 @@ pub enum ConsoleMsg {
     Reload,
     Reset,
     Exit,
+    /// List the files the script depends on
+    Deps,
+    /// Show rendering statistics for the last frame
+    Stats,
+    /// Set the soft and hard script time limits in milliseconds, or show them if None
+    Budget(Option<(u64, u64)>),
 }
 
 const PROMPT: &str = "> ";
@@ -39,10 +45,21 @@ fn parse_command(s: String) -> ConsoleMsg {
         "/exit" => ConsoleMsg::Exit,
         "/reload" => ConsoleMsg::Reload,
         "/reset" => ConsoleMsg::Reset,
+        "/deps" => ConsoleMsg::Deps,
+        "/stats" => ConsoleMsg::Stats,
+        _ if s.starts_with("/budget") => ConsoleMsg::Budget(parse_budget(&s)),
         _ => ConsoleMsg::Command(s),
     }
 }
 
+/// Parse "/budget <soft ms> <hard ms>"
+fn parse_budget(s: &str) -> Option<(u64, u64)> {
+    let mut args = s.split_whitespace().skip(1);
+    let soft = args.next()?.parse().ok()?;
+    let hard = args.next()?.parse().ok()?;
+    Some((soft, hard))
+}
+
 pub fn print_lua_ret(ret: mlua::Result<MultiValue>) {
     match ret {
         Ok(values) => {
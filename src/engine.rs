@@ -1,11 +1,28 @@
-use anyhow::Result;
+use crate::console::console_print;
+use crate::pipeline_cache;
+use crate::pipeline::{
+    create_pipeline, DeviceFeatures, PipelineDesc, PipelineOptions, INSTANCE_BINDING,
+    VERTEX_BINDING,
+};
+use crate::reflection::{self, InterfaceBinding, PipelineInterface, ShaderInput};
+use crate::vertex_layout::{AttributeFormat, VertexLayout};
+use anyhow::{format_err, Result};
 use defaults::FRAMES_IN_FLIGHT;
 use slotmap::{new_key_type, SecondaryMap};
+use std::collections::HashMap;
+use std::time::Instant;
 use watertender::memory;
 use watertender::prelude::*;
 
-// TODO: Make this expandable
-const MAX_TRANSFORMS: usize = 5000;
+/// Transform storage allocated up front for each frame in flight; it grows as needed
+const INITIAL_TRANSFORMS: usize = 5000;
+
+/// Binding of the per-frame scene data
+const FRAME_DATA_BINDING: u32 = 0;
+/// Binding of the transform storage buffer
+const TRANSFORM_BINDING: u32 = 1;
+/// Push constants hold the index of a draw's first transform
+const PUSH_CONSTANT_SIZE: u32 = std::mem::size_of::<u32>() as u32;
 
 new_key_type! {
     /// Handle for a Material (Draw commands)
@@ -13,10 +30,14 @@ new_key_type! {
 
     /// Handle for a Mesh (Draw content)
     pub struct Mesh;
+
+    /// Handle for a scene object (Persistent draw command)
+    pub struct Object;
 }
 
 impl mlua::UserData for Shader {}
 impl mlua::UserData for Mesh {}
+impl mlua::UserData for Object {}
 
 /// Transform data in column-major format
 pub type Transform = [[f32; 4]; 4];
@@ -27,11 +48,22 @@ pub const TRANSFORM_IDENTITY: Transform = [
     [0.0, 0.0, 0.0, 1.0],
 ];
 
-/// A single object to be drawn
+/// A single object to be drawn, possibly many times over
 pub struct DrawCmd {
     pub shader: Shader,
     pub geometry: DrawGeometry,
-    pub transform: Option<Transform>,
+    /// One transform per instance, so an empty list draws nothing. Without a list, a single
+    /// instance is drawn with the identity transform.
+    pub transforms: Option<Vec<Transform>>,
+    /// Extra per-instance attributes; one set for each transform
+    pub instance_data: Option<InstanceData>,
+}
+
+/// Per-instance vertex attributes
+pub struct InstanceData {
+    pub layout: VertexLayout,
+    /// Packed according to `layout`
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -49,78 +81,628 @@ pub enum DrawGeometry {
     }
 }
 
+/// A draw command whose pipeline is known, ready to be sorted
+struct Draw {
+    ordered: bool,
+    sort_key: (Shader, LayoutId, LayoutId, Option<Mesh>),
+    pipeline: vk::Pipeline,
+    geometry: DrawGeometry,
+    instances: CmdInstances,
+}
+
+/// Counters for the last frame drawn
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameStats {
+    pub draw_calls: u32,
+    pub pipeline_binds: u32,
+    /// Vertex and index buffers are bound together
+    pub buffer_binds: u32,
+    /// Pipeline and buffer binds skipped because they were already bound
+    pub binds_saved: u32,
+}
+
+impl std::fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Draw calls: {}, pipeline binds: {}, buffer binds: {}, binds saved: {}",
+            self.draw_calls, self.pipeline_binds, self.buffer_binds, self.binds_saved
+        )
+    }
+}
+
+/// Where to find the instances of a draw command
+#[derive(Copy, Clone)]
+struct CmdInstances {
+    /// Index of the first instance's transform; the rest follow it
+    first_transform: u32,
+    count: u32,
+    /// Layout of the command's instance data
+    layout: LayoutId,
+    /// Offset of the command's instance data in this frame's instance buffer
+    data_offset: u64,
+}
+
+impl DrawGeometry {
+    fn mesh(&self) -> Option<Mesh> {
+        match self {
+            DrawGeometry::Mesh { mesh, .. } => Some(*mesh),
+            DrawGeometry::Procedural { .. } => None,
+        }
+    }
+}
+
 /// A set of draw commands
 pub struct FramePacket {
     pub cmds: Vec<DrawCmd>,
     pub midi: [u32; 3],
     pub anim: f32,
+    pub timing: FrameTiming,
+    /// Mouse position in pixels
+    pub mouse: [f32; 2],
+}
+
+/// Timing information for a single frame
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameTiming {
+    /// Seconds since the engine started
+    pub time: f32,
+    /// Seconds since the last frame
+    pub delta_time: f32,
+    /// Number of frames since the engine started
+    pub frame: u32,
+}
+
+/// Measures frame timing
+pub struct FrameClock {
+    start: Instant,
+    last: Instant,
+    frame: u32,
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            start: now,
+            last: now,
+            frame: 0,
+        }
+    }
+
+    /// Begin a new frame, returning its timing
+    pub fn tick(&mut self) -> FrameTiming {
+        let now = Instant::now();
+        let timing = FrameTiming {
+            time: (now - self.start).as_secs_f32(),
+            delta_time: (now - self.last).as_secs_f32(),
+            frame: self.frame,
+        };
+        self.last = now;
+        self.frame = self.frame.wrapping_add(1);
+        timing
+    }
+}
+
+/// Mesh contents to be uploaded
+pub struct MeshData {
+    /// Interleaved vertex data, packed according to `layout`
+    pub vertices: Vec<u8>,
+    pub indices: Vec<u32>,
+    pub layout: VertexLayout,
+    /// Whether the mesh is expected to be rewritten often
+    pub dynamic: bool,
+}
+
+/// Index into the engine's list of known vertex layouts
+type LayoutId = usize;
+
+/// Layout of procedural draws, which have no vertex buffer, and of draws without instance data
+const NO_ATTRIBUTES: LayoutId = 0;
+
+/// A mesh and the layout of its vertices
+struct MeshEntry {
+    gpu: GpuMesh,
+    layout: LayoutId,
+}
+
+/// A compiled shader, along with a pipeline for each vertex layout it has been drawn with.
+/// Pipelines are created the first time a layout is used; None records a layout which didn't
+/// match the shader's inputs.
+struct ShaderEntry {
+    vertex_spv: Vec<u8>,
+    fragment_spv: Vec<u8>,
+    topology: vk::PrimitiveTopology,
+    inputs: Vec<ShaderInput>,
+    /// Keyed by vertex and instance layout
+    pipelines: HashMap<(LayoutId, LayoutId), Option<vk::Pipeline>>,
+    options: PipelineOptions,
+    /// Built from the fallback shader, as the shader has never compiled
+    fallback: bool,
+}
+
+impl ShaderEntry {
+    fn pipelines(&self) -> impl Iterator<Item = vk::Pipeline> + '_ {
+        self.pipelines.values().filter_map(|p| *p)
+    }
+}
+
+/// Mesh storage on the GPU
+enum GpuMesh {
+    /// Uploaded once through the staging buffer
+    Static(ManagedMesh),
+    /// Kept in host-visible memory so that it may be rewritten every frame
+    Dynamic(DynamicMesh),
+}
+
+impl GpuMesh {
+    /// Vertex buffer, index buffer and index count to draw with on the given frame in flight
+    fn buffers(&self, frame: usize) -> Option<(vk::Buffer, vk::Buffer, u32)> {
+        match self {
+            GpuMesh::Static(mesh) => Some((
+                mesh.vertices.instance(),
+                mesh.indices.instance(),
+                mesh.n_indices,
+            )),
+            GpuMesh::Dynamic(mesh) => {
+                let frame = &mesh.frames[frame];
+                let vertices = frame.vertices.as_ref()?;
+                let indices = frame.indices.as_ref()?;
+                Some((vertices.instance(), indices.instance(), frame.n_indices))
+            }
+        }
+    }
+}
+
+/// A mesh with a copy of its buffers for each frame in flight. Each copy is brought up to date
+/// with the latest contents the next time its frame comes around.
+struct DynamicMesh {
+    vertices: Vec<u8>,
+    indices: Vec<u32>,
+    frames: Vec<DynamicMeshFrame>,
+}
+
+#[derive(Default)]
+struct DynamicMeshFrame {
+    vertices: Option<HostBuffer>,
+    indices: Option<HostBuffer>,
+    n_indices: u32,
+    up_to_date: bool,
+}
+
+/// A host-visible buffer with room to grow
+struct HostBuffer {
+    buffer: ManagedBuffer,
+    capacity: usize,
+}
+
+impl DynamicMesh {
+    fn new(vertices: &[u8], indices: &[u32]) -> Self {
+        let mut instance = Self {
+            vertices: vec![],
+            indices: vec![],
+            frames: (0..FRAMES_IN_FLIGHT).map(|_| DynamicMeshFrame::default()).collect(),
+        };
+        instance.set_contents(vertices, indices);
+        instance
+    }
+
+    fn set_contents(&mut self, vertices: &[u8], indices: &[u32]) {
+        self.vertices = vertices.to_vec();
+        self.indices = indices.to_vec();
+        for frame in &mut self.frames {
+            frame.up_to_date = false;
+        }
+    }
+
+    /// Write the latest contents to the buffers for the given frame in flight, if needed. This
+    /// frame's buffers must no longer be in use by the GPU.
+    fn prepare_frame(&mut self, core: &SharedCore, frame: usize) -> Result<()> {
+        let frame = &mut self.frames[frame];
+        if frame.up_to_date {
+            return Ok(());
+        }
+
+        HostBuffer::write(
+            &mut frame.vertices,
+            core,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            &self.vertices,
+        )?;
+        HostBuffer::write(
+            &mut frame.indices,
+            core,
+            vk::BufferUsageFlags::INDEX_BUFFER,
+            bytemuck::cast_slice(&self.indices),
+        )?;
+        frame.n_indices = self.indices.len() as u32;
+        frame.up_to_date = true;
+
+        Ok(())
+    }
+}
+
+impl HostBuffer {
+    fn new(core: &SharedCore, size: usize, usage: vk::BufferUsageFlags) -> Result<Self> {
+        // Leave room to grow
+        const MIN_CAPACITY: usize = 256;
+        let capacity = size.next_power_of_two().max(MIN_CAPACITY);
+
+        let ci = vk::BufferCreateInfoBuilder::new()
+            .size(capacity as u64)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .usage(usage);
+        let buffer = ManagedBuffer::new(core.clone(), ci, memory::UsageFlags::UPLOAD)?;
+
+        Ok(Self { buffer, capacity })
+    }
+
+    /// Write to the start of the buffer, first replacing it with a larger one if the bytes don't
+    /// fit. The buffer must not be in use by the GPU. Returns true if the buffer was replaced.
+    fn write(
+        buffer: &mut Option<Self>,
+        core: &SharedCore,
+        usage: vk::BufferUsageFlags,
+        bytes: &[u8],
+    ) -> Result<bool> {
+        let fits = buffer.as_ref().map_or(false, |b| b.capacity >= bytes.len());
+        if !fits {
+            *buffer = Some(Self::new(core, bytes.len(), usage)?);
+        }
+
+        let buffer = buffer.as_mut().unwrap();
+        buffer.buffer.write_bytes(0, bytes)?;
+        Ok(!fits)
+    }
+
+    fn instance(&self) -> vk::Buffer {
+        self.buffer.instance()
+    }
+}
+
+/// GPU resources which may still be in use by frames in flight
+#[allow(dead_code)] // Some are only held in order to be dropped later
+enum Retired {
+    Mesh(GpuMesh),
+    Pipeline(vk::Pipeline),
+}
+
+/// SPIR-V of the shader drawn in place of shaders which have never compiled
+pub struct FallbackSpv {
+    /// Draws meshes, reading the position from location 0
+    pub vertex: Vec<u8>,
+    /// Draws a fullscreen triangle, for draws without a mesh
+    pub procedural_vertex: Vec<u8>,
+    pub fragment: Vec<u8>,
 }
 
 /// Rendering engine
 pub struct RenderEngine {
-    shaders: SecondaryMap<Shader, vk::Pipeline>,
-    meshes: SecondaryMap<Mesh, ManagedMesh>,
+    shaders: SecondaryMap<Shader, ShaderEntry>,
+    /// Drawn in place of shaders which have never compiled
+    fallback_spv: Option<FallbackSpv>,
+    meshes: SecondaryMap<Mesh, MeshEntry>,
+    /// Scene objects, drawn every frame after the frame's own commands
+    objects: SecondaryMap<Object, DrawCmd>,
+    /// Every vertex layout seen so far, indexed by LayoutId
+    layouts: Vec<VertexLayout>,
 
-    transforms: Vec<ManagedBuffer>,
+    /// Resources waiting to be destroyed, along with the frame number they were retired on
+    retired: Vec<(u64, Retired)>,
+    frame_count: u64,
+    stats: FrameStats,
+
+    /// Transforms for each frame in flight
+    transforms: Vec<Option<HostBuffer>>,
+    /// Per-instance attributes for each frame in flight
+    instance_buffers: Vec<Option<HostBuffer>>,
 
     descriptor_sets: Vec<vk::DescriptorSet>,
     descriptor_pool: vk::DescriptorPool,
     descriptor_set_layout: vk::DescriptorSetLayout,
 
     pipeline_layout: vk::PipelineLayout,
+    /// Used for every pipeline, and saved to disk on shutdown
+    pipeline_cache: vk::PipelineCache,
     scene_ubo: FrameDataUbo<SceneData>,
+    extent: vk::Extent2D,
     camera: MultiPlatformCamera,
     starter_kit: StarterKit,
 }
 
-/// Scene data passed to shaders
+/// Scene data passed to shaders. Field order and padding follow std140, matching the `PerFrame`
+/// block declared in shaders/common.glsl:
+/// ```glsl
+/// mat4 camera[2];
+/// float anim;
+/// float time;
+/// float delta_time;
+/// uint frame;
+/// vec2 resolution;
+/// vec2 mouse;
+/// uvec3 midi;
+/// ```
 #[repr(C)]
 #[derive(Copy, Clone, Debug)]
 struct SceneData {
     cameras: [f32; 4 * 4 * 2],
     anim: f32,
+    time: f32,
+    delta_time: f32,
+    frame: u32,
+    resolution: [f32; 2],
+    mouse: [f32; 2],
     midi: [u32; 3],
+    _pad: u32,
 }
 
 unsafe impl bytemuck::Zeroable for SceneData {}
 unsafe impl bytemuck::Pod for SceneData {}
 
 impl RenderEngine {
-    /// Add a mesh, or replace an existing one with the same name
-    pub fn add_mesh(&mut self, vertices: &[Vertex], indices: &[u32], key: Mesh) -> Result<()> {
-        // Mesh uploads
-        let cmd = self.starter_kit.current_command_buffer();
-        let mesh = upload_mesh(&mut self.starter_kit.staging_buffer, cmd, vertices, indices)?;
-        self.meshes.insert(key, mesh);
+    /// Add a mesh, or replace the contents of an existing one with the same name. Dynamic meshes
+    /// are written directly to host-visible memory instead of going through the staging buffer.
+    pub fn add_mesh(&mut self, data: &MeshData, key: Mesh) -> Result<()> {
+        let layout = self.layout_id(&data.layout);
+
+        let gpu = if data.dynamic {
+            if let Some(entry) = self.meshes.get_mut(key) {
+                if let GpuMesh::Dynamic(mesh) = &mut entry.gpu {
+                    mesh.set_contents(&data.vertices, &data.indices);
+                    entry.layout = layout;
+                    return Ok(());
+                }
+            }
+            GpuMesh::Dynamic(DynamicMesh::new(&data.vertices, &data.indices))
+        } else {
+            // Mesh uploads
+            let cmd = self.starter_kit.current_command_buffer();
+            let staging = &mut self.starter_kit.staging_buffer;
+
+            let ci = vk::BufferCreateInfoBuilder::new()
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .usage(vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST);
+            let vertices = staging.upload_buffer_pod(cmd, ci, &data.vertices)?;
+
+            let ci = vk::BufferCreateInfoBuilder::new()
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .usage(vk::BufferUsageFlags::INDEX_BUFFER | vk::BufferUsageFlags::TRANSFER_DST);
+            let indices = staging.upload_buffer_pod(cmd, ci, &data.indices)?;
+
+            GpuMesh::Static(ManagedMesh {
+                vertices,
+                indices,
+                n_indices: data.indices.len() as u32,
+            })
+        };
+
+        if let Some(old) = self.meshes.insert(key, MeshEntry { gpu, layout }) {
+            self.retire(Retired::Mesh(old.gpu));
+        }
+
         Ok(())
     }
 
-    /// Add a shader, or replace an existing one with the same name
+    /// Find the id of the given layout, remembering it if it's new
+    fn layout_id(&mut self, layout: &VertexLayout) -> LayoutId {
+        match self.layouts.iter().position(|l| l == layout) {
+            Some(id) => id,
+            None => {
+                self.layouts.push(layout.clone());
+                self.layouts.len() - 1
+            }
+        }
+    }
+
+    /// Get the pipeline for drawing vertices and instances of the given layouts with the given
+    /// shader, creating it if this is the first time they are used together. Returns None if they
+    /// don't match or the pipeline could not be created; the reason is reported only once.
+    fn pipeline_for(
+        &mut self,
+        key: Shader,
+        vertex: LayoutId,
+        instance: LayoutId,
+    ) -> Option<vk::Pipeline> {
+        let entry = self.shaders.get_mut(key)?;
+        if let Some(pipeline) = entry.pipelines.get(&(vertex, instance)) {
+            return *pipeline;
+        }
+
+        let vertex_layout = &self.layouts[vertex];
+        let instance_layout = &self.layouts[instance];
+        let fallback_spv = self.fallback_spv.as_ref().filter(|_| entry.fallback);
+        let (vertex_spv, check) = match fallback_spv {
+            // Draws without a mesh get a fullscreen triangle instead
+            Some(spv) if vertex == NO_ATTRIBUTES => (&spv.procedural_vertex, Ok(())),
+            // The fallback only reads the position, from location 0
+            Some(_) => (
+                &entry.vertex_spv,
+                match vertex_layout.attributes.first() {
+                    Some(attr) if attr.format == AttributeFormat::F32 => Ok(()),
+                    _ => Err("the shader failed to compile, and the fallback can only draw meshes with float positions".to_string()),
+                },
+            ),
+            None => (
+                &entry.vertex_spv,
+                reflection::check_vertex_layout(&entry.inputs, vertex_layout, instance_layout),
+            ),
+        };
+        // The closure would otherwise borrow all of `self`, while `entry` borrows `self.shaders`
+        let core = &self.starter_kit.core;
+        let render_pass = self.starter_kit.render_pass;
+        let pipeline_layout = self.pipeline_layout;
+        let pipeline_cache = self.pipeline_cache;
+        let pipeline = check
+            .map_err(|e| format_err!("{}", e))
+            .and_then(|()| {
+                let desc = PipelineDesc {
+                    vertex_spv,
+                    fragment_spv: &entry.fragment_spv,
+                    topology: entry.topology,
+                    vertex_layout,
+                    instance_layout,
+                    options: &entry.options,
+                };
+                create_pipeline(core, &desc, render_pass, pipeline_layout, pipeline_cache)
+            });
+
+        let pipeline = match pipeline {
+            Ok(p) => Some(p),
+            Err(e) => {
+                console_print(&format!("Cannot draw with shader; {:#}", e));
+                None
+            }
+        };
+
+        entry.pipelines.insert((vertex, instance), pipeline);
+        pipeline
+    }
+
+    /// Destroy a resource once no frame in flight can be using it
+    fn retire(&mut self, resource: Retired) {
+        self.retired.push((self.frame_count, resource));
+    }
+
+    /// Destroy retired resources which are no longer in use. Must be called after waiting for the
+    /// current frame in flight.
+    fn collect_garbage(&mut self) {
+        let frame_count = self.frame_count;
+        let (done, in_use): (Vec<_>, Vec<_>) = std::mem::take(&mut self.retired)
+            .into_iter()
+            .partition(|(retired_on, _)| retired_on + FRAMES_IN_FLIGHT as u64 <= frame_count);
+        self.retired = in_use;
+
+        for (_, resource) in done {
+            self.destroy(resource);
+        }
+    }
+
+    fn destroy(&self, resource: Retired) {
+        match resource {
+            Retired::Mesh(_) => (),
+            Retired::Pipeline(pipeline) => unsafe {
+                self.starter_kit.core.device.destroy_pipeline(Some(pipeline), None);
+            },
+        }
+    }
+
+    /// Add a shader, or replace an existing one with the same name. Pipelines are built once
+    /// the shader is drawn with, as they depend on the vertex layout of the mesh.
     pub fn add_shader(
         &mut self,
         vertex_spv: &[u8],
         fragment_spv: &[u8],
         topo: vk::PrimitiveTopology,
+        options: &PipelineOptions,
         key: Shader,
     ) -> Result<()> {
-        let pipeline = shader(
-            &self.starter_kit.core,
-            vertex_spv,
-            fragment_spv,
-            topo,
-            self.starter_kit.render_pass,
-            self.pipeline_layout,
-        )?;
-        let maybe_old_pipeline = self.shaders.insert(key, pipeline);
-        unsafe {
-            let core = &self.starter_kit.core;
-            if maybe_old_pipeline.is_some() {
-                core.device.queue_wait_idle(core.queue).result()?;
-                core.device.destroy_pipeline(maybe_old_pipeline, None);
-            }
+        let entry = ShaderEntry {
+            options: options.clone(),
+            inputs: reflection::vertex_inputs(vertex_spv)?,
+            vertex_spv: vertex_spv.to_vec(),
+            fragment_spv: fragment_spv.to_vec(),
+            topology: topo,
+            pipelines: HashMap::new(),
+            fallback: false,
+        };
+        if let Some(old) = self.shaders.insert(key, entry) {
+            self.retire_shader(old);
         }
         Ok(())
     }
+
+    /// The descriptors and push constants available to shaders
+    pub fn interface(&self) -> PipelineInterface {
+        PipelineInterface {
+            bindings: vec![
+                InterfaceBinding {
+                    binding: FRAME_DATA_BINDING,
+                    descriptor_type: vk::DescriptorType::UNIFORM_BUFFER,
+                    name: "PerFrame",
+                    size: Some(std::mem::size_of::<SceneData>() as u32),
+                },
+                InterfaceBinding {
+                    binding: TRANSFORM_BINDING,
+                    descriptor_type: vk::DescriptorType::STORAGE_BUFFER,
+                    name: "transforms",
+                    size: None,
+                },
+            ],
+            push_constant_size: PUSH_CONSTANT_SIZE,
+            push_constant_stages: vk::ShaderStageFlags::VERTEX,
+        }
+    }
+
+    /// Set the shader drawn in place of shaders which have never compiled
+    pub fn set_fallback_shader(&mut self, spv: FallbackSpv) {
+        self.fallback_spv = Some(spv);
+    }
+
+    /// Draw a shader which failed to compile with the fallback shader, unless an earlier version
+    /// of it compiled; that version is kept instead.
+    pub fn add_fallback_shader(
+        &mut self,
+        topo: vk::PrimitiveTopology,
+        options: &PipelineOptions,
+        key: Shader,
+    ) -> Result<()> {
+        if self.shaders.get(key).map_or(false, |entry| !entry.fallback) {
+            return Ok(());
+        }
+
+        let (vertex_spv, fragment_spv) = match &self.fallback_spv {
+            Some(spv) => (spv.vertex.clone(), spv.fragment.clone()),
+            None => return Ok(()),
+        };
+
+        // Opaque and filled, but primitive restart must match the script's indices
+        let options = PipelineOptions {
+            primitive_restart: options.primitive_restart,
+            ..PipelineOptions::default()
+        };
+        self.add_shader(&vertex_spv, &fragment_spv, topo, &options, key)?;
+        self.shaders[key].fallback = true;
+        Ok(())
+    }
+
+    fn retire_shader(&mut self, entry: ShaderEntry) {
+        for pipeline in entry.pipelines() {
+            self.retire(Retired::Pipeline(pipeline));
+        }
+    }
+
+    /// Add a scene object, or replace an existing one
+    pub fn set_object(&mut self, key: Object, cmd: DrawCmd) {
+        self.objects.insert(key, cmd);
+    }
+
+    pub fn remove_object(&mut self, key: Object) {
+        self.objects.remove(key);
+    }
+
+    pub fn clear_objects(&mut self) {
+        self.objects.clear();
+    }
+
+    /// Free a mesh once no frame in flight is using it
+    pub fn free_mesh(&mut self, key: Mesh) {
+        if let Some(mesh) = self.meshes.remove(key) {
+            self.retire(Retired::Mesh(mesh.gpu));
+        }
+    }
+
+    /// Free a shader once no frame in flight is using it
+    pub fn free_shader(&mut self, key: Shader) {
+        if let Some(entry) = self.shaders.remove(key) {
+            self.retire_shader(entry);
+        }
+    }
 }
 
 impl RenderEngine {
@@ -135,18 +717,14 @@ impl RenderEngine {
         let scene_ubo = FrameDataUbo::new(core.clone(), defaults::FRAMES_IN_FLIGHT)?;
 
         // Transforms data
-        let total_size = std::mem::size_of::<Transform>() * MAX_TRANSFORMS;
-        let ci = vk::BufferCreateInfoBuilder::new()
-            .size(total_size as u64)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE)
-            .usage(vk::BufferUsageFlags::STORAGE_BUFFER);
+        let total_size = std::mem::size_of::<Transform>() * INITIAL_TRANSFORMS;
         let transforms = (0..FRAMES_IN_FLIGHT)
-            .map(|_| ManagedBuffer::new(core.clone(), ci, memory::UsageFlags::UPLOAD))
+            .map(|_| {
+                HostBuffer::new(core, total_size, vk::BufferUsageFlags::STORAGE_BUFFER).map(Some)
+            })
             .collect::<Result<Vec<_>>>()?;
 
         // Create descriptor set layout
-        const FRAME_DATA_BINDING: u32 = 0;
-        const TRANSFORM_BINDING: u32 = 1;
         let bindings = [
             vk::DescriptorSetLayoutBindingBuilder::new()
                 .binding(FRAME_DATA_BINDING)
@@ -198,36 +776,27 @@ impl RenderEngine {
         // Write descriptor sets
         for (frame, &descriptor_set) in descriptor_sets.iter().enumerate() {
             let frame_data_bi = [scene_ubo.descriptor_buffer_info(frame)];
-            let transform_bi = [vk::DescriptorBufferInfoBuilder::new()
-                .buffer(transforms[frame].instance())
-                .offset(0)
-                .range(vk::WHOLE_SIZE)];
-
-            let writes = [
-                vk::WriteDescriptorSetBuilder::new()
-                    .buffer_info(&frame_data_bi)
-                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-                    .dst_set(descriptor_set)
-                    .dst_binding(FRAME_DATA_BINDING)
-                    .dst_array_element(0),
-                vk::WriteDescriptorSetBuilder::new()
-                    .buffer_info(&transform_bi)
-                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
-                    .dst_set(descriptor_set)
-                    .dst_binding(TRANSFORM_BINDING)
-                    .dst_array_element(0),
-            ];
+            let writes = [vk::WriteDescriptorSetBuilder::new()
+                .buffer_info(&frame_data_bi)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .dst_set(descriptor_set)
+                .dst_binding(FRAME_DATA_BINDING)
+                .dst_array_element(0)];
 
             unsafe {
                 core.device.update_descriptor_sets(&writes, &[]);
             }
+
+            if let Some(buffer) = &transforms[frame] {
+                write_transform_descriptor(core, descriptor_set, buffer.instance());
+            }
         }
 
         // Pipeline layout
         let push_constant_ranges = [vk::PushConstantRangeBuilder::new()
             .stage_flags(vk::ShaderStageFlags::VERTEX)
             .offset(0)
-            .size(std::mem::size_of::<[f32; 4 * 4]>() as u32)];
+            .size(PUSH_CONSTANT_SIZE)];
 
         let descriptor_set_layouts = [descriptor_set_layout];
         let create_info = vk::PipelineLayoutCreateInfoBuilder::new()
@@ -237,17 +806,28 @@ impl RenderEngine {
         let pipeline_layout =
             unsafe { core.device.create_pipeline_layout(&create_info, None, None) }.result()?;
 
+        let pipeline_cache = pipeline_cache::load(core)?;
+
         let instance = Self {
             camera,
             pipeline_layout,
+            pipeline_cache,
             scene_ubo,
+            extent: vk::Extent2D::default(),
             starter_kit,
             transforms,
+            instance_buffers: (0..FRAMES_IN_FLIGHT).map(|_| None).collect(),
             descriptor_set_layout,
             descriptor_sets,
             descriptor_pool,
             meshes: SecondaryMap::new(),
+            objects: SecondaryMap::new(),
             shaders: SecondaryMap::new(),
+            fallback_spv: None,
+            layouts: vec![VertexLayout::default()],
+            retired: vec![],
+            frame_count: 0,
+            stats: FrameStats::default(),
         };
 
         Ok(instance)
@@ -260,29 +840,80 @@ impl RenderEngine {
         platform: Platform<'_>,
         packet: FramePacket,
     ) -> Result<PlatformReturn> {
-        // Collect and write transforms
+        // Scene objects are drawn along with the frame's commands. They are put back once the
+        // command buffer is written.
+        let objects = std::mem::take(&mut self.objects);
+        let n_cmds = packet.cmds.len() + objects.len();
+
+        // Collect transforms and instance data
         let mut positions: Vec<Transform> = vec![TRANSFORM_IDENTITY];
-        let mut cmd_transform_indices: Vec<u32> = Vec::with_capacity(packet.cmds.len());
-        for cmd in &packet.cmds {
-            if let Some(transform) = cmd.transform {
-                let idx = positions.len().min(MAX_TRANSFORMS) as u32;
-                cmd_transform_indices.push(idx);
-                positions.push(transform);
-            } else {
-                cmd_transform_indices.push(0);
+        let mut instance_bytes: Vec<u8> = vec![];
+        let mut cmd_instances: Vec<CmdInstances> = Vec::with_capacity(n_cmds);
+        for cmd in packet.cmds.iter().chain(objects.values()) {
+            let mut instances = CmdInstances {
+                first_transform: 0,
+                count: 1,
+                layout: NO_ATTRIBUTES,
+                data_offset: 0,
+            };
+
+            if let Some(transforms) = &cmd.transforms {
+                instances.first_transform = positions.len() as u32;
+                instances.count = transforms.len() as u32;
+                positions.extend_from_slice(transforms);
             }
+
+            if let Some(data) = &cmd.instance_data {
+                instances.layout = self.layout_id(&data.layout);
+                instances.data_offset = instance_bytes.len() as u64;
+                instance_bytes.extend_from_slice(&data.data);
+            }
+
+            cmd_instances.push(instances);
         }
-        assert_eq!(packet.cmds.len(), cmd_transform_indices.len());
+        assert_eq!(n_cmds, cmd_instances.len());
+
+        let command_buffer_start = self.starter_kit.begin_command_buffer(frame)?;
+        self.collect_garbage();
 
-        if positions.len() > MAX_TRANSFORMS {
-            eprintln!("Too many positions! {} exceeded {}", positions.len(), MAX_TRANSFORMS);
-            positions.truncate(MAX_TRANSFORMS);
+        // Bring dynamic meshes up to date for this frame, now that its buffers are no longer in
+        // use
+        for mesh in self.meshes.values_mut() {
+            if let GpuMesh::Dynamic(mesh) = &mut mesh.gpu {
+                mesh.prepare_frame(&self.starter_kit.core, self.starter_kit.frame)?;
+            }
         }
 
-        self.transforms[self.starter_kit.frame]
-            .write_bytes(0, bytemuck::cast_slice(positions.as_slice()))?;
+        // This frame's transform buffer is no longer in use, so it may be replaced. The frame's
+        // descriptor set must then point at the new one.
+        let frame_idx = self.starter_kit.frame;
+        let replaced = HostBuffer::write(
+            &mut self.transforms[frame_idx],
+            &self.starter_kit.core,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+            bytemuck::cast_slice(positions.as_slice()),
+        )?;
+        if replaced {
+            if let Some(buffer) = &self.transforms[frame_idx] {
+                write_transform_descriptor(
+                    &self.starter_kit.core,
+                    self.descriptor_sets[frame_idx],
+                    buffer.instance(),
+                );
+            }
+        }
 
-        let command_buffer_start = self.starter_kit.begin_command_buffer(frame)?;
+        // Likewise for the instance buffer
+        let instance_buffer = &mut self.instance_buffers[self.starter_kit.frame];
+        if !instance_bytes.is_empty() {
+            HostBuffer::write(
+                instance_buffer,
+                &self.starter_kit.core,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                &instance_bytes,
+            )?;
+        }
+        let instance_buffer = instance_buffer.as_ref().map(|b| b.instance());
 
         // Write command buffer
         let command_buffer = command_buffer_start.command_buffer;
@@ -296,25 +927,80 @@ impl RenderEngine {
                 &[],
             );
 
-            // Draw cmds
-            // TODO: Batch draw calls per pipeline...
-            for (cmd, transf_idx) in packet.cmds.into_iter().zip(cmd_transform_indices) {
+            // Find the pipeline for each command
+            let mut draws: Vec<Draw> = Vec::with_capacity(n_cmds);
+            let cmds = packet.cmds.iter().chain(objects.values());
+            for (cmd, instances) in cmds.zip(cmd_instances) {
+                // An empty `instances` list draws nothing
+                if instances.count == 0 {
+                    continue;
+                }
 
-                let shader = match self.shaders.get(cmd.shader) {
-                    Some(s) => s,
+                // The vertex and instance layouts decide which of the shader's pipelines to use
+                let layout = match cmd.geometry {
+                    DrawGeometry::Mesh { mesh, .. } => match self.meshes.get(mesh) {
+                        Some(m) => m.layout,
+                        None => {
+                            //eprintln!("Mesh unavailable!");
+                            continue;
+                        }
+                    },
+                    DrawGeometry::Procedural { .. } => NO_ATTRIBUTES,
+                };
+
+                let pipeline = match self.pipeline_for(cmd.shader, layout, instances.layout) {
+                    Some(p) => p,
                     None => {
                         //eprintln!("Shader unavailable!");
                         continue;
                     }
                 };
 
-                core.device.cmd_bind_pipeline(
-                    command_buffer,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    *shader,
-                );
+                draws.push(Draw {
+                    ordered: self.shaders[cmd.shader].options.ordered,
+                    sort_key: (cmd.shader, layout, instances.layout, cmd.geometry.mesh()),
+                    pipeline,
+                    geometry: cmd.geometry,
+                    instances,
+                });
+            }
+
+            // Group draws sharing pipelines and meshes. Ordered draws go last, in the order they
+            // were given; the sort is stable.
+            draws.sort_by_key(|d| match d.ordered {
+                false => (false, Some(d.sort_key)),
+                true => (true, None),
+            });
+
+            let mut stats = FrameStats::default();
+            let mut bound_pipeline = None;
+            let mut bound_buffers = None;
+            for draw in draws {
+                if bound_pipeline != Some(draw.pipeline) {
+                    core.device.cmd_bind_pipeline(
+                        command_buffer,
+                        vk::PipelineBindPoint::GRAPHICS,
+                        draw.pipeline,
+                    );
+                    bound_pipeline = Some(draw.pipeline);
+                    stats.pipeline_binds += 1;
+                } else {
+                    stats.binds_saved += 1;
+                }
+
+                let instances = draw.instances;
+                if instances.layout != NO_ATTRIBUTES {
+                    if let Some(buffer) = instance_buffer {
+                        core.device.cmd_bind_vertex_buffers(
+                            command_buffer,
+                            INSTANCE_BINDING,
+                            &[buffer],
+                            &[instances.data_offset],
+                        );
+                    }
+                }
 
-                let push_const = [transf_idx];
+                let push_const = [instances.first_transform];
                 // TODO: Make this a shortcut
                 core.device.cmd_push_constants(
                     command_buffer,
@@ -325,43 +1011,62 @@ impl RenderEngine {
                     push_const.as_ptr() as _,
                 );
 
-                match cmd.geometry {
+                match draw.geometry {
                     DrawGeometry::Mesh { mesh, max_idx } => {
-                        let mesh = match self.meshes.get(mesh) {
-                            Some(m) => m,
+                        let buffers = self.meshes
+                            .get(mesh)
+                            .and_then(|m| m.gpu.buffers(self.starter_kit.frame));
+                        let (vertices, indices, n_indices) = match buffers {
+                            Some(b) => b,
                             None => {
                                 //eprintln!("Mesh unavailable!");
                                 continue;
                             }
                         };
 
-                        core.device.cmd_bind_vertex_buffers(
-                            command_buffer,
-                            0,
-                            &[mesh.vertices.instance()],
-                            &[0],
-                        );
+                        if bound_buffers != Some((vertices, indices)) {
+                            core.device.cmd_bind_vertex_buffers(
+                                command_buffer,
+                                VERTEX_BINDING,
+                                &[vertices],
+                                &[0],
+                            );
 
-                        core.device.cmd_bind_index_buffer(
-                            command_buffer,
-                            mesh.indices.instance(),
-                            0,
-                            vk::IndexType::UINT32,
-                        );
+                            core.device.cmd_bind_index_buffer(
+                                command_buffer,
+                                indices,
+                                0,
+                                vk::IndexType::UINT32,
+                            );
+                            bound_buffers = Some((vertices, indices));
+                            stats.buffer_binds += 1;
+                        } else {
+                            stats.binds_saved += 1;
+                        }
 
                         let max_idx = match max_idx {
-                            Some(max) => max.min(mesh.n_indices),
-                            None => mesh.n_indices,
+                            Some(max) => max.min(n_indices),
+                            None => n_indices,
                         };
 
-                        core.device.cmd_draw_indexed(command_buffer, max_idx, 1, 0, 0, 0);
+                        core.device.cmd_draw_indexed(
+                            command_buffer,
+                            max_idx,
+                            instances.count,
+                            0,
+                            0,
+                            0,
+                        );
                     },
                     DrawGeometry::Procedural { n_verts } => {
-                        core.device.cmd_draw(command_buffer, n_verts, 1, 0, 0);
+                        core.device.cmd_draw(command_buffer, n_verts, instances.count, 0, 0);
                     },
                 }
+                stats.draw_calls += 1;
             }
+            self.stats = stats;
         }
+        self.objects = objects;
 
         let (ret, cameras) = self.camera.get_matrices(&platform)?;
 
@@ -370,20 +1075,44 @@ impl RenderEngine {
             &SceneData {
                 cameras,
                 anim: packet.anim,
+                time: packet.timing.time,
+                delta_time: packet.timing.delta_time,
+                frame: packet.timing.frame,
+                resolution: [self.extent.width as f32, self.extent.height as f32],
+                mouse: packet.mouse,
                 midi: packet.midi,
+                _pad: 0,
             },
         )?;
 
         // End draw cmds
         self.starter_kit.end_command_buffer(command_buffer_start)?;
+        self.frame_count += 1;
 
         Ok(ret)
     }
 
     pub fn swapchain_resize(&mut self, images: Vec<vk::Image>, extent: vk::Extent2D) -> Result<()> {
+        self.extent = extent;
         self.starter_kit.swapchain_resize(images, extent)
     }
 
+    /// Counters for the last frame drawn
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Current framebuffer size
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Optional features enabled on the device
+    pub fn features(&self) -> DeviceFeatures {
+        // Watertender creates the device without enabling any optional core features
+        DeviceFeatures::default()
+    }
+
     pub fn event(
         &mut self,
         mut event: PlatformEvent<'_, '_>,
@@ -400,16 +1129,46 @@ impl RenderEngine {
     }
 }
 
+/// Point the transform binding of a descriptor set at the given buffer. The set must not be in use
+/// by the GPU.
+fn write_transform_descriptor(core: &Core, descriptor_set: vk::DescriptorSet, buffer: vk::Buffer) {
+    let transform_bi = [vk::DescriptorBufferInfoBuilder::new()
+        .buffer(buffer)
+        .offset(0)
+        .range(vk::WHOLE_SIZE)];
+
+    let writes = [vk::WriteDescriptorSetBuilder::new()
+        .buffer_info(&transform_bi)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .dst_set(descriptor_set)
+        .dst_binding(TRANSFORM_BINDING)
+        .dst_array_element(0)];
+
+    unsafe {
+        core.device.update_descriptor_sets(&writes, &[]);
+    }
+}
+
 impl Drop for RenderEngine {
     fn drop(&mut self) {
+        if let Err(e) = pipeline_cache::save(&self.starter_kit.core, self.pipeline_cache) {
+            console_print(&format!("Failed to save pipeline cache; {:#}", e));
+        }
+
         unsafe {
+            self.starter_kit.core.device.destroy_pipeline_cache(Some(self.pipeline_cache), None);
             self.starter_kit.core.device.destroy_descriptor_pool(Some(self.descriptor_pool), None);
             self.starter_kit.core.device.destroy_descriptor_set_layout(Some(self.descriptor_set_layout), None);
             self.starter_kit.core.device.destroy_pipeline_layout(Some(self.pipeline_layout), None);
-            for (_, pipeline) in self.shaders.drain() {
-                self.starter_kit.core.device.destroy_pipeline(Some(pipeline), None);
+            for (_, entry) in self.shaders.drain() {
+                for pipeline in entry.pipelines() {
+                    self.starter_kit.core.device.destroy_pipeline(Some(pipeline), None);
+                }
             }
         }
+        for (_, resource) in std::mem::take(&mut self.retired) {
+            self.destroy(resource);
+        }
     }
 }
 
@@ -419,6 +1178,8 @@ impl Default for FramePacket {
             cmds: vec![],
             anim: 0.,
             midi: [0; 3],
+            timing: FrameTiming::default(),
+            mouse: [0.; 2],
         }
     }
 }
@@ -0,0 +1,163 @@
+use mlua::prelude::*;
+use std::collections::HashSet;
+use watertender::mainloop::PlatformEvent;
+use watertender::winit::event::{
+    DeviceEvent, ElementState, Event, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+/// Input state which scripts may poll, instead of tracking events themselves
+#[derive(Default, Debug)]
+pub struct InputState {
+    keys_down: HashSet<String>,
+    buttons_down: HashSet<String>,
+    mouse_pos: [f64; 2],
+    mouse_delta: [f64; 2],
+}
+
+impl InputState {
+    /// Update the state from a platform event
+    pub fn handle_event(&mut self, event: &PlatformEvent) {
+        let event = match event {
+            PlatformEvent::Winit(event) => event,
+            #[allow(unreachable_patterns)]
+            _ => return,
+        };
+
+        match event {
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::KeyboardInput { input, .. } => {
+                    if let Some(key) = input.virtual_keycode {
+                        match input.state {
+                            ElementState::Pressed => self.keys_down.insert(key_name(key)),
+                            ElementState::Released => self.keys_down.remove(&key_name(key)),
+                        };
+                    }
+                }
+                WindowEvent::MouseInput { button, state, .. } => {
+                    match state {
+                        ElementState::Pressed => self.buttons_down.insert(button_name(*button)),
+                        ElementState::Released => self.buttons_down.remove(&button_name(*button)),
+                    };
+                }
+                WindowEvent::CursorMoved { position, .. } => {
+                    self.mouse_pos = [position.x, position.y];
+                }
+                // Releases which happen while unfocused are never seen
+                WindowEvent::Focused(false) => {
+                    self.keys_down.clear();
+                    self.buttons_down.clear();
+                }
+                _ => (),
+            },
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } => {
+                self.mouse_delta[0] += delta.0;
+                self.mouse_delta[1] += delta.1;
+            }
+            _ => (),
+        }
+    }
+
+    /// Reset accumulated values; call once per frame after the script has seen them
+    pub fn end_frame(&mut self) {
+        self.mouse_delta = [0.; 2];
+    }
+
+    /// Whether the key with the given name (see `key_name()`) is held
+    pub fn key_down(&self, name: &str) -> bool {
+        self.keys_down.contains(&name.to_lowercase())
+    }
+
+    /// Names of all held mouse buttons (see `button_name()`)
+    pub fn buttons_down(&self) -> impl Iterator<Item = &str> {
+        self.buttons_down.iter().map(|s| s.as_str())
+    }
+
+    /// Cursor position in pixels, relative to the top-left of the window
+    pub fn mouse_pos(&self) -> [f64; 2] {
+        self.mouse_pos
+    }
+
+    /// Raw mouse motion accumulated since the last frame
+    pub fn mouse_delta(&self) -> [f64; 2] {
+        self.mouse_delta
+    }
+}
+
+/// Name used for a key in Lua, e.g. "w", "space" or "lshift"
+pub fn key_name(key: VirtualKeyCode) -> String {
+    format!("{:?}", key).to_lowercase()
+}
+
+/// Name used for a mouse button in Lua, e.g. "left" or "4"
+pub fn button_name(button: MouseButton) -> String {
+    match button {
+        MouseButton::Left => "left".into(),
+        MouseButton::Right => "right".into(),
+        MouseButton::Middle => "middle".into(),
+        MouseButton::Other(n) => n.to_string(),
+    }
+}
+
+/// Translate a platform event into a plain Lua table, if it is one scripts are interested in
+pub fn event_to_lua<'lua>(
+    lua: &'lua Lua,
+    event: &PlatformEvent,
+) -> LuaResult<Option<LuaTable<'lua>>> {
+    let event = match event {
+        PlatformEvent::Winit(event) => event,
+        #[allow(unreachable_patterns)]
+        _ => return Ok(None),
+    };
+
+    let event = match event {
+        Event::WindowEvent { event, .. } => event,
+        _ => return Ok(None),
+    };
+
+    let table = lua.create_table()?;
+    match event {
+        WindowEvent::KeyboardInput { input, .. } => {
+            table.set("type", "key")?;
+            if let Some(key) = input.virtual_keycode {
+                table.set("key", key_name(key))?;
+            }
+            table.set("scancode", input.scancode)?;
+            table.set("pressed", input.state == ElementState::Pressed)?;
+        }
+        WindowEvent::MouseInput { button, state, .. } => {
+            table.set("type", "mouse_button")?;
+            table.set("button", button_name(*button))?;
+            table.set("pressed", *state == ElementState::Pressed)?;
+        }
+        WindowEvent::CursorMoved { position, .. } => {
+            table.set("type", "mouse_motion")?;
+            table.set("x", position.x)?;
+            table.set("y", position.y)?;
+        }
+        WindowEvent::MouseWheel { delta, .. } => {
+            let (x, y, pixels) = match delta {
+                MouseScrollDelta::LineDelta(x, y) => (*x as f64, *y as f64, false),
+                MouseScrollDelta::PixelDelta(pos) => (pos.x, pos.y, true),
+            };
+            table.set("type", "scroll")?;
+            table.set("x", x)?;
+            table.set("y", y)?;
+            table.set("pixels", pixels)?;
+        }
+        WindowEvent::Resized(size) => {
+            table.set("type", "resize")?;
+            table.set("width", size.width)?;
+            table.set("height", size.height)?;
+        }
+        WindowEvent::Focused(focused) => {
+            table.set("type", "focus")?;
+            table.set("focused", *focused)?;
+        }
+        _ => return Ok(None),
+    }
+
+    Ok(Some(table))
+}
@@ -0,0 +1,372 @@
+use crate::engine::Transform;
+use mlua::prelude::*;
+use mlua::{FromLuaMulti, MetaMethod, ToLuaMulti, UserData, UserDataMethods};
+use nalgebra::{Matrix4, Point3, Quaternion, Unit, UnitQuaternion, Vector2, Vector3, Vector4};
+
+/// 2D vector userdata
+#[derive(Copy, Clone, Debug)]
+pub struct LuaVec2(pub Vector2<f32>);
+
+/// 3D vector userdata
+#[derive(Copy, Clone, Debug)]
+pub struct LuaVec3(pub Vector3<f32>);
+
+/// 4D vector userdata
+#[derive(Copy, Clone, Debug)]
+pub struct LuaVec4(pub Vector4<f32>);
+
+/// 4x4 matrix userdata, column-major like `Transform`
+#[derive(Copy, Clone, Debug)]
+pub struct LuaMat4(pub Matrix4<f32>);
+
+/// Rotation quaternion userdata
+#[derive(Copy, Clone, Debug)]
+pub struct LuaQuat(pub UnitQuaternion<f32>);
+
+impl From<LuaMat4> for Transform {
+    fn from(m: LuaMat4) -> Self {
+        m.0.into()
+    }
+}
+
+/// Read a number, accepting integers too
+fn number(value: &LuaValue) -> Option<f32> {
+    match value {
+        LuaValue::Number(n) => Some(*n as f32),
+        LuaValue::Integer(i) => Some(*i as f32),
+        _ => None,
+    }
+}
+
+/// Read userdata of type `T` out of a value
+fn userdata<T: UserData + Copy + 'static>(value: &LuaValue) -> Option<T> {
+    match value {
+        LuaValue::UserData(ud) => ud.borrow::<T>().ok().map(|v| *v),
+        _ => None,
+    }
+}
+
+fn bad_operands(op: &str, a: &LuaValue, b: &LuaValue) -> LuaError {
+    LuaError::RuntimeError(format!(
+        "Cannot {} {} and {}",
+        op,
+        a.type_name(),
+        b.type_name()
+    ))
+}
+
+/// Implements the operations common to all vector types
+macro_rules! impl_vec {
+    ($name:ident, $lua_name:literal, [$($field:literal),*]) => {
+        impl $name {
+            fn field_index(key: &str) -> LuaResult<usize> {
+                [$($field),*]
+                    .iter()
+                    .position(|f| *f == key)
+                    .ok_or_else(|| LuaError::RuntimeError(format!(
+                        "{} has no field \"{}\"",
+                        $lua_name,
+                        key
+                    )))
+            }
+        }
+
+        impl UserData for $name {
+            fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+                methods.add_method("dot", |_, this, other: $name| Ok(this.0.dot(&other.0)));
+                methods.add_method("length", |_, this, ()| Ok(this.0.norm()));
+                methods.add_method("normalize", |_, this, ()| Ok($name(this.0.normalize())));
+                methods.add_method("lerp", |_, this, (other, t): ($name, f32)| {
+                    Ok($name(this.0.lerp(&other.0, t)))
+                });
+                methods.add_method("unpack", |_, this, ()| {
+                    Ok(LuaMultiValue::from_vec(
+                        this.0.iter().map(|v| LuaValue::Number(*v as f64)).collect(),
+                    ))
+                });
+
+                methods.add_meta_method(MetaMethod::Index, |_, this, key: String| {
+                    Ok(this.0[$name::field_index(&key)?])
+                });
+                methods.add_meta_method_mut(
+                    MetaMethod::NewIndex,
+                    |_, this, (key, value): (String, f32)| {
+                        this.0[$name::field_index(&key)?] = value;
+                        Ok(())
+                    },
+                );
+
+                methods.add_meta_method(MetaMethod::Add, |_, this, other: $name| {
+                    Ok($name(this.0 + other.0))
+                });
+                methods.add_meta_method(MetaMethod::Sub, |_, this, other: $name| {
+                    Ok($name(this.0 - other.0))
+                });
+                methods.add_meta_method(MetaMethod::Unm, |_, this, ()| Ok($name(-this.0)));
+                methods.add_meta_method(MetaMethod::Div, |_, this, scalar: f32| {
+                    Ok($name(this.0 / scalar))
+                });
+                methods.add_meta_function(MetaMethod::Mul, |_, (a, b): (LuaValue, LuaValue)| {
+                    match (userdata::<$name>(&a), userdata::<$name>(&b), number(&a), number(&b)) {
+                        (Some(a), Some(b), _, _) => Ok($name(a.0.component_mul(&b.0))),
+                        (Some(v), _, _, Some(s)) | (_, Some(v), Some(s), _) => Ok($name(v.0 * s)),
+                        _ => Err(bad_operands("multiply", &a, &b)),
+                    }
+                });
+                methods.add_meta_method(MetaMethod::Eq, |_, this, other: $name| {
+                    Ok(this.0 == other.0)
+                });
+                methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+                    let parts: Vec<String> = this.0.iter().map(|v| v.to_string()).collect();
+                    Ok(format!("{}({})", $lua_name, parts.join(", ")))
+                });
+
+                add_vec_methods(methods);
+            }
+        }
+    };
+}
+
+impl_vec!(LuaVec2, "vec2", ["x", "y"]);
+impl_vec!(LuaVec3, "vec3", ["x", "y", "z"]);
+impl_vec!(LuaVec4, "vec4", ["x", "y", "z", "w"]);
+
+/// Methods specific to one vector type
+trait VecMethods: Sized {
+    fn add<'lua, M: UserDataMethods<'lua, Self>>(_methods: &mut M) {}
+}
+
+fn add_vec_methods<'lua, T: VecMethods, M: UserDataMethods<'lua, T>>(methods: &mut M) {
+    T::add(methods)
+}
+
+impl VecMethods for LuaVec2 {}
+impl VecMethods for LuaVec4 {}
+impl VecMethods for LuaVec3 {
+    fn add<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("cross", |_, this, other: LuaVec3| {
+            Ok(LuaVec3(this.0.cross(&other.0)))
+        });
+    }
+}
+
+impl UserData for LuaQuat {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("inverse", |_, this, ()| Ok(LuaQuat(this.0.inverse())));
+        methods.add_method("slerp", |_, this, (other, t): (LuaQuat, f32)| {
+            Ok(LuaQuat(this.0.slerp(&other.0, t)))
+        });
+        methods.add_method("to_mat4", |_, this, ()| Ok(LuaMat4(this.0.to_homogeneous())));
+        methods.add_method("unpack", |_, this, ()| {
+            let q = this.0.quaternion();
+            Ok((q.i, q.j, q.k, q.w))
+        });
+
+        methods.add_meta_function(MetaMethod::Mul, |lua, (a, b): (LuaValue, LuaValue)| {
+            let q = match userdata::<LuaQuat>(&a) {
+                Some(q) => q.0,
+                None => return Err(bad_operands("multiply", &a, &b)),
+            };
+            if let Some(other) = userdata::<LuaQuat>(&b) {
+                LuaQuat(q * other.0).to_lua(lua)
+            } else if let Some(v) = userdata::<LuaVec3>(&b) {
+                LuaVec3(q * v.0).to_lua(lua)
+            } else {
+                Err(bad_operands("multiply", &a, &b))
+            }
+        });
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+            let q = this.0.quaternion();
+            Ok(format!("quat({}, {}, {}, {})", q.i, q.j, q.k, q.w))
+        });
+    }
+}
+
+impl UserData for LuaMat4 {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("inverse", |_, this, ()| {
+            this.0
+                .try_inverse()
+                .map(LuaMat4)
+                .ok_or_else(|| LuaError::RuntimeError("Matrix is not invertible".into()))
+        });
+        methods.add_method("transpose", |_, this, ()| Ok(LuaMat4(this.0.transpose())));
+        methods.add_method("transform_point", |_, this, p: LuaVec3| {
+            Ok(LuaVec3(this.0.transform_point(&Point3::from(p.0)).coords))
+        });
+        methods.add_method("transform_vector", |_, this, v: LuaVec3| {
+            Ok(LuaVec3(this.0.transform_vector(&v.0)))
+        });
+        methods.add_method("get", |_, this, (row, col): (usize, usize)| {
+            this.0
+                .get((row.wrapping_sub(1), col.wrapping_sub(1)))
+                .copied()
+                .ok_or_else(|| LuaError::RuntimeError("Matrix index out of range".into()))
+        });
+        methods.add_method("to_table", |_, this, ()| Ok(this.0.as_slice().to_vec()));
+
+        methods.add_meta_function(MetaMethod::Mul, |lua, (a, b): (LuaValue, LuaValue)| {
+            match (userdata::<LuaMat4>(&a), number(&a)) {
+                (Some(m), _) => {
+                    if let Some(other) = userdata::<LuaMat4>(&b) {
+                        LuaMat4(m.0 * other.0).to_lua(lua)
+                    } else if let Some(v) = userdata::<LuaVec4>(&b) {
+                        LuaVec4(m.0 * v.0).to_lua(lua)
+                    } else if let Some(s) = number(&b) {
+                        LuaMat4(m.0 * s).to_lua(lua)
+                    } else {
+                        Err(bad_operands("multiply", &a, &b))
+                    }
+                }
+                (None, Some(s)) => match userdata::<LuaMat4>(&b) {
+                    Some(m) => LuaMat4(m.0 * s).to_lua(lua),
+                    None => Err(bad_operands("multiply", &a, &b)),
+                },
+                _ => Err(bad_operands("multiply", &a, &b)),
+            }
+        });
+        methods.add_meta_method(MetaMethod::Eq, |_, this, other: LuaMat4| Ok(this.0 == other.0));
+        methods.add_meta_method(MetaMethod::ToString, |_, this, ()| {
+            Ok(format!("mat4{}", this.0))
+        });
+    }
+}
+
+/// Either a vector or a single number applied to every axis
+fn scale_arg(value: &LuaValue) -> LuaResult<Vector3<f32>> {
+    if let Some(s) = number(value) {
+        Ok(Vector3::repeat(s))
+    } else if let Some(v) = userdata::<LuaVec3>(value) {
+        Ok(v.0)
+    } else {
+        Err(LuaError::RuntimeError(format!(
+            "Expected a number or vec3 for scale, got {}",
+            value.type_name()
+        )))
+    }
+}
+
+/// Create a table of constructors which may also be called directly, e.g. `vec3(1, 2, 3)` or
+/// `mat4.identity()`
+fn constructor_table<'lua, A, R, F>(lua: &'lua Lua, call: F) -> LuaResult<LuaTable<'lua>>
+where
+    A: FromLuaMulti<'lua>,
+    R: ToLuaMulti<'lua>,
+    F: 'static + Fn(&'lua Lua, A) -> LuaResult<R>,
+{
+    let table = lua.create_table()?;
+    let meta = lua.create_table()?;
+    let call = lua.create_function(move |lua, (_, args): (LuaValue, A)| call(lua, args))?;
+    meta.set("__call", call)?;
+    table.set_metatable(Some(meta));
+    Ok(table)
+}
+
+/// Install the `vec2`, `vec3`, `vec4`, `quat` and `mat4` globals
+pub fn install(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+
+    let vec2 = constructor_table(lua, |_, (x, y): (f32, f32)| Ok(LuaVec2(Vector2::new(x, y))))?;
+    globals.set("vec2", vec2)?;
+
+    let vec3 = constructor_table(lua, |_, (x, y, z): (f32, f32, f32)| {
+        Ok(LuaVec3(Vector3::new(x, y, z)))
+    })?;
+    globals.set("vec3", vec3)?;
+
+    let vec4 = constructor_table(lua, |_, (x, y, z, w): (f32, f32, f32, f32)| {
+        Ok(LuaVec4(Vector4::new(x, y, z, w)))
+    })?;
+    globals.set("vec4", vec4)?;
+
+    // Quaternions
+    let quat = constructor_table(lua, |_, (x, y, z, w): (f32, f32, f32, f32)| {
+        Ok(LuaQuat(UnitQuaternion::from_quaternion(Quaternion::new(w, x, y, z))))
+    })?;
+    quat.set(
+        "identity",
+        lua.create_function(|_, ()| Ok(LuaQuat(UnitQuaternion::identity())))?,
+    )?;
+    quat.set(
+        "axis_angle",
+        lua.create_function(|_, (axis, angle): (LuaVec3, f32)| {
+            Ok(LuaQuat(UnitQuaternion::from_axis_angle(
+                &Unit::new_normalize(axis.0),
+                angle,
+            )))
+        })?,
+    )?;
+    quat.set(
+        "euler",
+        lua.create_function(|_, (roll, pitch, yaw): (f32, f32, f32)| {
+            Ok(LuaQuat(UnitQuaternion::from_euler_angles(roll, pitch, yaw)))
+        })?,
+    )?;
+    globals.set("quat", quat)?;
+
+    // Matrices
+    let mat4 = constructor_table(lua, |_, values: Option<Vec<f32>>| match values {
+        None => Ok(LuaMat4(Matrix4::identity())),
+        Some(values) if values.len() == 16 => Ok(LuaMat4(Matrix4::from_column_slice(&values))),
+        Some(values) => Err(LuaError::RuntimeError(format!(
+            "mat4() expects 16 numbers in column-major order, got {}",
+            values.len()
+        ))),
+    })?;
+    mat4.set(
+        "identity",
+        lua.create_function(|_, ()| Ok(LuaMat4(Matrix4::identity())))?,
+    )?;
+    mat4.set(
+        "translation",
+        lua.create_function(|_, v: LuaVec3| Ok(LuaMat4(Matrix4::new_translation(&v.0))))?,
+    )?;
+    mat4.set(
+        "scale",
+        lua.create_function(|_, s: LuaValue| {
+            Ok(LuaMat4(Matrix4::new_nonuniform_scaling(&scale_arg(&s)?)))
+        })?,
+    )?;
+    mat4.set(
+        "rotation",
+        lua.create_function(|_, (axis, angle): (LuaVec3, f32)| {
+            Ok(LuaMat4(Matrix4::from_axis_angle(
+                &Unit::new_normalize(axis.0),
+                angle,
+            )))
+        })?,
+    )?;
+    mat4.set(
+        "perspective",
+        lua.create_function(|_, (fovy, aspect, near, far): (f32, f32, f32, f32)| {
+            Ok(LuaMat4(Matrix4::new_perspective(aspect, fovy, near, far)))
+        })?,
+    )?;
+    mat4.set(
+        "look_at",
+        lua.create_function(|_, (eye, target, up): (LuaVec3, LuaVec3, LuaVec3)| {
+            Ok(LuaMat4(Matrix4::look_at_rh(
+                &Point3::from(eye.0),
+                &Point3::from(target.0),
+                &up.0,
+            )))
+        })?,
+    )?;
+    mat4.set(
+        "trs",
+        lua.create_function(|_, (t, r, s): (LuaVec3, LuaQuat, Option<LuaValue>)| {
+            let scale = match s {
+                Some(s) => scale_arg(&s)?,
+                None => Vector3::repeat(1.),
+            };
+            Ok(LuaMat4(
+                Matrix4::new_translation(&t.0)
+                    * r.0.to_homogeneous()
+                    * Matrix4::new_nonuniform_scaling(&scale),
+            ))
+        })?,
+    )?;
+    globals.set("mat4", mat4)?;
+
+    Ok(())
+}
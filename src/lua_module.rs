@@ -1,13 +1,28 @@
 use crate::console::console_print;
-use crate::engine::{DrawCmd, DrawGeometry, Mesh, RenderEngine, Shader, Transform};
+use crate::input::{event_to_lua, InputState};
+use crate::lua_math::{self, LuaMat4};
+use crate::script_budget::{Budget, ScriptBudget};
+use crate::script_deps::{self, ScriptDeps};
+use crate::engine::{
+    DrawCmd, DrawGeometry, FrameTiming, InstanceData, Mesh, MeshData, Object, RenderEngine, Shader,
+    Transform,
+};
+use crate::pipeline::{
+    parse_cull_mode, parse_front_face, parse_polygon_mode, BlendMode, DepthBias, DeviceFeatures,
+    PipelineOptions,
+};
+use crate::mesh_validation::{
+    check_index_count, check_integer_attributes, is_strip, parse_primitive, validate_mesh,
+    MeshArgs, PRIMITIVE_RESTART,
+};
+use crate::vertex_layout::{AttributeFormat, VertexAttribute, VertexLayout};
 use anyhow::{format_err, Context, Result, bail};
 use mlua::prelude::*;
-use slotmap::SlotMap;
-use std::{cell::RefCell, path::PathBuf, rc::Rc};
+use slotmap::{SecondaryMap, SlotMap};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, path::{Path, PathBuf}, rc::Rc};
 use watertender::mainloop::PlatformEvent;
-use watertender::vertex::Vertex;
-use watertender::vk::PrimitiveTopology;
-use crate::shader_update_calc::UniquePipeline;
+use watertender::vk::{self, Extent2D, PrimitiveTopology};
+use crate::shader_update_calc::{Defines, ShaderSource, ShaderStatus, UniquePipeline};
 use mlua::Table;
 
 /// Lua code
@@ -15,7 +30,12 @@ pub struct LuaModule {
     new_data: Rc<RefCell<NewDataLua>>,
     pub lua: &'static Lua,
     frame_fn: Option<LuaFunction<'static>>,
+    event_fn: Option<LuaFunction<'static>>,
+    input: Rc<RefCell<InputState>>,
+    budget: ScriptBudget,
+    deps: Rc<RefCell<ScriptDeps>>,
     path: PathBuf,
+    features: DeviceFeatures,
 }
 
 /// Deferred operations on the engine (Can't/don't want to call engine directly...)
@@ -23,55 +43,472 @@ pub struct LuaModule {
 struct NewDataLua {
     /// This is where the key for the mesh comes from in the entire program; these handles link the
     /// Lua data to the engine data.
-    meshes: SlotMap<Mesh, ()>,
-    shaders: SlotMap<Shader, ()>,
+    meshes: SlotMap<Mesh, MeshInfo>,
+    shaders: SlotMap<Shader, ShaderInfo>,
     added_meshes: Vec<(Mesh, MeshData)>,
     tracked_shaders: Vec<(Shader, UniquePipeline)>,
+    freed_meshes: Vec<Mesh>,
+    freed_shaders: Vec<Shader>,
+    /// Outcome of each shader's latest compilation
+    shader_status: SecondaryMap<Shader, ShaderStatus>,
+    /// Shaders built from source strings, by call site
+    inline_shaders: HashMap<String, (Shader, UniquePipeline)>,
+    /// Sites which have called shader() since the last reload
+    inline_sites_used: HashSet<String>,
+    /// Descriptions of each scene object, as given to spawn() and update_object()
+    objects: SlotMap<Object, LuaRegistryKey>,
+    updated_objects: Vec<(Object, DrawCmd)>,
+    despawned_objects: Vec<Object>,
+    /// Optional features enabled on the device, which some primitives need
+    features: DeviceFeatures,
+}
+
+/// Render updates accumulated during Lua callbacks
+pub struct RenderUpdates {
+    pub added_meshes: Vec<(Mesh, MeshData)>,
+    pub tracked_shaders: Vec<(Shader, UniquePipeline)>,
+    pub freed_meshes: Vec<Mesh>,
+    pub freed_shaders: Vec<Shader>,
+    /// Scene objects which were spawned or changed
+    pub updated_objects: Vec<(Object, DrawCmd)>,
+    pub despawned_objects: Vec<Object>,
 }
 
 fn lua_err(e: mlua::Error) -> anyhow::Error {
     format_err!("Lua error: {}", e)
 }
 
-pub type MeshData = (Vec<Vertex>, Vec<u32>);
+/// What we remember about each mesh
+struct MeshInfo {
+    dynamic: bool,
+    layout: VertexLayout,
+    primitive: Option<PrimitiveTopology>,
+    n_indices: usize,
+    /// Whether the indices contain PRIMITIVE_RESTART
+    restart: bool,
+}
+
+/// What we remember about each shader
+struct ShaderInfo {
+    primitive: PrimitiveTopology,
+    primitive_restart: bool,
+}
+
+/// Options table passed to add_mesh()
+struct MeshOptions {
+    /// Whether the mesh is expected to be rewritten often
+    dynamic: bool,
+    /// How the vertex list is divided into attributes
+    layout: VertexLayout,
+    /// Primitive the mesh is meant to be drawn as, if the index count should be checked up front
+    primitive: Option<PrimitiveTopology>,
+}
+
+impl Default for MeshOptions {
+    fn default() -> Self {
+        Self {
+            dynamic: false,
+            layout: VertexLayout::pos_color(),
+            primitive: None,
+        }
+    }
+}
+
+fn decode_mesh_options(
+    table: Option<LuaTable>,
+    features: DeviceFeatures,
+) -> LuaResult<MeshOptions> {
+    let table = match table {
+        Some(t) => t,
+        None => return Ok(MeshOptions::default()),
+    };
+
+    let layout = match table.get::<_, Option<LuaTable>>("layout")? {
+        Some(layout) => decode_vertex_layout(layout)?,
+        None => VertexLayout::pos_color(),
+    };
+
+    let primitive = match table.get::<_, Option<String>>("primitive")? {
+        Some(p) => Some(parse_primitive(&p, features).map_err(LuaError::external)?),
+        None => None,
+    };
+
+    Ok(MeshOptions {
+        dynamic: table.get::<_, Option<bool>>("dynamic")?.unwrap_or(false),
+        layout,
+        primitive,
+    })
+}
+
+/// Decode the options table passed to track_shader(), rejecting options which need device
+/// features that aren't enabled
+fn decode_pipeline_options(
+    table: Option<LuaTable>,
+    features: DeviceFeatures,
+) -> LuaResult<PipelineOptions> {
+    let table = match table {
+        Some(t) => t,
+        None => return Ok(PipelineOptions::default()),
+    };
+
+    let defaults = PipelineOptions::default();
+    let blend = get_enum_option(&table, "blend", BlendMode::parse, "opaque, alpha or additive")?
+        .unwrap_or(defaults.blend);
+
+    let line_width = table.get::<_, Option<f32>>("line_width")?.unwrap_or(defaults.line_width);
+    if !(line_width.is_finite() && line_width > 0.0) {
+        return Err(LuaError::external(format!(
+            "`line_width` must be a positive number, got {}",
+            line_width
+        )));
+    }
+    if line_width != 1.0 && !features.wide_lines {
+        return Err(LuaError::external(
+            "`line_width` other than 1 needs the wideLines device feature, which isn't enabled",
+        ));
+    }
+
+    let polygon_mode =
+        get_enum_option(&table, "polygon", parse_polygon_mode, "fill, line or point")?
+            .unwrap_or(defaults.polygon_mode);
+    if polygon_mode != vk::PolygonMode::FILL && !features.fill_mode_non_solid {
+        return Err(LuaError::external(
+            "`polygon` other than fill needs the fillModeNonSolid device feature, which isn't \
+             enabled",
+        ));
+    }
+
+    // Either a constant factor, or a table of `{ constant, slope }`
+    let depth_bias = match table.get::<_, LuaValue>("depth_bias")? {
+        LuaValue::Nil => None,
+        LuaValue::Table(bias) => Some(DepthBias {
+            constant: bias.get::<_, Option<f32>>("constant")?.unwrap_or(0.0),
+            slope: bias.get::<_, Option<f32>>("slope")?.unwrap_or(0.0),
+        }),
+        LuaValue::Number(constant) => Some(DepthBias {
+            constant: constant as f32,
+            slope: 0.0,
+        }),
+        LuaValue::Integer(constant) => Some(DepthBias {
+            constant: constant as f32,
+            slope: 0.0,
+        }),
+        other => {
+            return Err(LuaError::external(format!(
+                "`depth_bias` must be a number or a table of {{ constant, slope }}, got {}",
+                other.type_name()
+            )))
+        }
+    };
+    if let Some(bias) = depth_bias {
+        if !(bias.constant.is_finite() && bias.slope.is_finite()) {
+            return Err(LuaError::external("`depth_bias` must be finite"));
+        }
+    }
+
+    Ok(PipelineOptions {
+        // Alpha blending depends on what was drawn before it
+        ordered: table
+            .get::<_, Option<bool>>("ordered")?
+            .unwrap_or(blend == BlendMode::Alpha),
+        blend,
+        depth_test: table.get::<_, Option<bool>>("depth_test")?.unwrap_or(defaults.depth_test),
+        depth_write: table.get::<_, Option<bool>>("depth_write")?.unwrap_or(defaults.depth_write),
+        cull_mode: get_enum_option(&table, "cull", parse_cull_mode, "none, front, back or both")?
+            .unwrap_or(defaults.cull_mode),
+        front_face: get_enum_option(&table, "front_face", parse_front_face, "cw or ccw")?
+            .unwrap_or(defaults.front_face),
+        polygon_mode,
+        line_width,
+        depth_bias,
+        primitive_restart: table.get::<_, Option<bool>>("restart")?.unwrap_or(false),
+    })
+}
+
+/// Decode the `defines` table of track_shader()'s options. `true` defines a macro without a value,
+/// numbers and strings give it one, and `false` leaves it undefined.
+fn decode_defines(table: Option<&LuaTable>) -> LuaResult<Defines> {
+    let mut defines = Defines::new();
+    let table = match table {
+        Some(t) => t,
+        None => return Ok(defines),
+    };
+
+    let list: LuaTable = match table.get::<_, Option<LuaTable>>("defines")? {
+        Some(l) => l,
+        None => return Ok(defines),
+    };
+
+    for pair in list.pairs::<String, LuaValue>() {
+        let (name, value) = pair?;
+        let value = match value {
+            LuaValue::Boolean(false) => continue,
+            LuaValue::Boolean(true) => None,
+            LuaValue::Integer(i) => Some(i.to_string()),
+            LuaValue::Number(n) => Some(n.to_string()),
+            LuaValue::String(s) => Some(s.to_str()?.to_string()),
+            other => {
+                return Err(LuaError::external(format!(
+                    "Define {} must be a boolean, number or string, got {}",
+                    name,
+                    other.type_name()
+                )))
+            }
+        };
+        defines.insert(name, value);
+    }
+
+    Ok(defines)
+}
+
+/// Read an optional string field, and parse it as one of a set of names
+fn get_enum_option<T>(
+    table: &LuaTable,
+    key: &str,
+    parse: fn(&str) -> Option<T>,
+    expected: &str,
+) -> LuaResult<Option<T>> {
+    match table.get::<_, Option<String>>(key)? {
+        Some(name) => parse(&name).map(Some).ok_or_else(|| {
+            LuaError::external(format!("`{}` must be one of {}, got {}", key, expected, name))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Decode a list of attributes; `{ { name = "pos", components = 3, format = "f32" }, ... }`
+fn decode_vertex_layout(table: LuaTable) -> LuaResult<VertexLayout> {
+    let mut attributes = vec![];
+    for (idx, attr) in table.sequence_values::<LuaTable>().enumerate() {
+        let attr = attr?;
+        let err = |msg: String| LuaError::external(format!("Vertex attribute {}: {}", idx + 1, msg));
+
+        let name: String = attr
+            .get::<_, Option<String>>("name")?
+            .unwrap_or_else(|| format!("attribute {}", idx + 1));
+
+        let components: u32 = attr
+            .get::<_, Option<u32>>("components")?
+            .ok_or_else(|| err("missing `components`".into()))?;
+        if !(1..=4).contains(&components) {
+            return Err(err(format!("components must be 1 through 4, got {}", components)));
+        }
+
+        let format = match attr.get::<_, Option<String>>("format")? {
+            Some(f) => AttributeFormat::parse(&f)
+                .ok_or_else(|| err(format!("unrecognized format {}", f)))?,
+            None => AttributeFormat::F32,
+        };
+
+        attributes.push(VertexAttribute {
+            name,
+            components,
+            format,
+        });
+    }
+
+    if attributes.is_empty() {
+        return Err(LuaError::external("Vertex layout must have at least one attribute"));
+    }
+
+    Ok(VertexLayout { attributes })
+}
 
 impl LuaModule {
-    pub fn new(path: PathBuf) -> Result<Self> {
-        let lua = Lua::new().into_static();
+    pub fn new(
+        path: PathBuf,
+        input: Rc<RefCell<InputState>>,
+        budget: Budget,
+        features: DeviceFeatures,
+    ) -> Result<Self> {
+        // The debug library is only loaded so that shader() can find its caller; see
+        // hide_debug_library()
+        let libs = LuaStdLib::ALL_SAFE | LuaStdLib::DEBUG;
+        let lua = unsafe { Lua::unsafe_new_with(libs) }.into_static();
+        hide_debug_library(lua).map_err(lua_err)?;
+        let budget = ScriptBudget::install(lua, budget).map_err(lua_err)?;
+        lua_math::install(lua).map_err(lua_err)?;
+
+        let deps = Rc::new(RefCell::new(ScriptDeps::new(&path)));
+        script_deps::install(lua, deps.clone()).map_err(lua_err)?;
 
         // TODO: Use scoped functions!
-        let new_data = Rc::new(RefCell::new(NewDataLua::default()));
+        let new_data = Rc::new(RefCell::new(NewDataLua {
+            features,
+            ..NewDataLua::default()
+        }));
         
         // Mesh creator function
         let new_data_clone = new_data.clone();
         let create_mesh_fn = lua
-            .create_function(move |_, (vertices, indices): (Vec<f32>, Vec<u32>)| {
-                Ok(new_data_clone
+            .create_function(move |_, (vertices, indices, options): (Vec<f64>, Vec<u32>, Option<LuaTable>)| {
+                let options = decode_mesh_options(options, features)?;
+                new_data_clone
                     .borrow_mut()
-                    .add_mesh(vertices, indices))
+                    .add_mesh(vertices, indices, options)
+                    .map_err(LuaError::external)
             })
             .map_err(lua_err)?;
         lua.globals().set("add_mesh", create_mesh_fn).map_err(lua_err)?;
+
+        // Mesh updater function
+        let new_data_clone = new_data.clone();
+        let update_mesh_fn = lua
+            .create_function(move |_, (mesh, vertices, indices): (Mesh, Vec<f64>, Vec<u32>)| {
+                new_data_clone
+                    .borrow_mut()
+                    .update_mesh(mesh, vertices, indices)
+                    .map_err(LuaError::external)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("update_mesh", update_mesh_fn).map_err(lua_err)?;
  
         // Shader creator function
         let new_data_clone = new_data.clone();
         let create_mesh_fn = lua
-            .create_function(move |_, (vert_path, frag_path, primitive): (String, String, String)| {
+            .create_function(move |_, (vert_path, frag_path, primitive, options): (String, String, String, Option<LuaTable>)| {
+                let defines = decode_defines(options.as_ref())?;
+                let options = decode_pipeline_options(options, features)?;
                 Ok(new_data_clone
                     .borrow_mut()
-                    .track_shader(vert_path, frag_path, primitive)
+                    .track_shader(vert_path, frag_path, primitive, options, defines)
                     .map_err(|e| mlua::Error::external(e))
                 )
             })
             .map_err(lua_err)?;
         lua.globals().set("track_shader", create_mesh_fn).map_err(lua_err)?;
 
+        // Shaders from source strings
+        let new_data_clone = new_data.clone();
+        let inline_shader_fn = lua
+            .create_function(move |lua, (vert_src, frag_src, primitive, options): (String, String, String, Option<LuaTable>)| {
+                let site = match options.as_ref() {
+                    Some(t) => t.get::<_, Option<String>>("name")?,
+                    None => None,
+                };
+                let site = match site {
+                    Some(name) => name,
+                    None => call_site(lua)?,
+                };
+                let defines = decode_defines(options.as_ref())?;
+                let options = decode_pipeline_options(options, features)?;
+                new_data_clone
+                    .borrow_mut()
+                    .inline_shader(site, vert_src, frag_src, primitive, options, defines)
+                    .map_err(LuaError::external)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("shader", inline_shader_fn).map_err(lua_err)?;
+
+        // Resource freeing functions
+        let new_data_clone = new_data.clone();
+        let free_mesh_fn = lua
+            .create_function(move |_, mesh: Mesh| {
+                new_data_clone
+                    .borrow_mut()
+                    .free_mesh(mesh)
+                    .map_err(LuaError::external)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("free_mesh", free_mesh_fn).map_err(lua_err)?;
+
+        let new_data_clone = new_data.clone();
+        let free_shader_fn = lua
+            .create_function(move |_, shader: Shader| {
+                new_data_clone
+                    .borrow_mut()
+                    .free_shader(shader)
+                    .map_err(LuaError::external)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("free_shader", free_shader_fn).map_err(lua_err)?;
+
+        let new_data_clone = new_data.clone();
+        let shader_status_fn = lua
+            .create_function(move |lua, shader: Shader| {
+                new_data_clone.borrow().shader_status(lua, shader)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("shader_status", shader_status_fn).map_err(lua_err)?;
+
+        // Separates strips in index lists
+        lua.globals().set("PRIMITIVE_RESTART", PRIMITIVE_RESTART).map_err(lua_err)?;
+
+        // Scene objects
+        let new_data_clone = new_data.clone();
+        let spawn_fn = lua
+            .create_function(move |lua, desc: LuaTable| {
+                new_data_clone.borrow_mut().spawn(lua, desc)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("spawn", spawn_fn).map_err(lua_err)?;
+
+        let new_data_clone = new_data.clone();
+        let update_object_fn = lua
+            .create_function(move |lua, (object, fields): (Object, LuaTable)| {
+                new_data_clone.borrow_mut().update_object(lua, object, fields)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("update_object", update_object_fn).map_err(lua_err)?;
+
+        let new_data_clone = new_data.clone();
+        let despawn_fn = lua
+            .create_function(move |lua, object: Object| {
+                new_data_clone
+                    .borrow_mut()
+                    .despawn(lua, object)
+                    .map_err(LuaError::external)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("despawn", despawn_fn).map_err(lua_err)?;
+
+        // Polled input state
+        let input_clone = input.clone();
+        let key_down_fn = lua
+            .create_function(move |_, name: String| Ok(input_clone.borrow().key_down(&name)))
+            .map_err(lua_err)?;
+        lua.globals().set("key_down", key_down_fn).map_err(lua_err)?;
+
+        let input_clone = input.clone();
+        let mouse_pos_fn = lua
+            .create_function(move |_, ()| {
+                let [x, y] = input_clone.borrow().mouse_pos();
+                Ok((x, y))
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("mouse_pos", mouse_pos_fn).map_err(lua_err)?;
+
+        let input_clone = input.clone();
+        let mouse_delta_fn = lua
+            .create_function(move |_, ()| {
+                let [x, y] = input_clone.borrow().mouse_delta();
+                Ok((x, y))
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("mouse_delta", mouse_delta_fn).map_err(lua_err)?;
+
+        let input_clone = input.clone();
+        let mouse_buttons_fn = lua
+            .create_function(move |lua, ()| {
+                let buttons = lua.create_table()?;
+                for name in input_clone.borrow().buttons_down() {
+                    buttons.set(name, true)?;
+                }
+                Ok(buttons)
+            })
+            .map_err(lua_err)?;
+        lua.globals().set("mouse_buttons", mouse_buttons_fn).map_err(lua_err)?;
 
         let mut instance = LuaModule {
             path,
             lua,
             frame_fn: None,
+            event_fn: None,
+            input,
+            budget,
+            deps,
             new_data,
+            features,
         };
 
         instance.reload();
@@ -80,29 +517,81 @@ impl LuaModule {
     }
 
     pub fn reset(&mut self) -> Result<()> {
-        *self = LuaModule::new(self.path.clone())?;
+        *self = LuaModule::new(
+            self.path.clone(),
+            self.input.clone(),
+            self.budget.budget(),
+            self.features,
+        )?;
         self.reload();
         Ok(())
     }
 
     pub fn reload(&mut self) {
         match self.try_reload() {
-            Err(e) => console_print(&format!("Reload error: {}", e)),
+            Err(e) => {
+                // Don't keep running the old callbacks alongside a half-loaded script
+                self.frame_fn = None;
+                self.event_fn = None;
+                console_print(&format!("Reload error: {}", e))
+            }
             Ok(_) => console_print("Reload successful."),
         }
     }
 
+    /// Evaluate a chunk of code, such as a console command
+    pub fn eval(&self, src: &str) -> mlua::Result<mlua::MultiValue<'static>> {
+        self.budget.call("Command", || self.lua.load(src).eval())
+    }
+
+    /// Whether the script used the given file during the last reload
+    pub fn depends_on(&self, path: &Path) -> bool {
+        self.deps.borrow().contains(path)
+    }
+
+    /// Files used by the script during the last reload
+    pub fn dependencies(&self) -> Vec<PathBuf> {
+        self.deps.borrow().files().map(|p| p.to_path_buf()).collect()
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.budget.budget()
+    }
+
+    pub fn set_budget(&self, budget: Budget) {
+        self.budget.set_budget(budget);
+    }
+
     pub fn try_reload(&mut self) -> Result<()> {
+        // The new script may give each shader() call site new sources
+        self.new_data.borrow_mut().inline_sites_used.clear();
+
         // Keep users from shooting themselves in the foot, by deleting the content which is always
         // expected to be there _before_ loading their new code. This means that if they renamed
         // the function, it will no longer be there.
         let globals = self.lua.globals();
         globals.set("reload", mlua::Value::Nil).map_err(lua_err)?;
         globals.set("frame", mlua::Value::Nil).map_err(lua_err)?;
+        globals.set("event", mlua::Value::Nil).map_err(lua_err)?;
 
-        self.lua
-            .load(&std::fs::read_to_string(&self.path).context("Failed to load script")?)
-            .eval::<mlua::MultiValue>()
+        // Start tracking dependencies afresh, and make sure required modules are actually
+        // reloaded instead of coming from package.loaded
+        let modules = self.deps.borrow_mut().reset();
+        let loaded: LuaTable = globals
+            .get::<_, LuaTable>("package")
+            .and_then(|package| package.get("loaded"))
+            .map_err(lua_err)?;
+        for module in modules {
+            loaded.set(module, mlua::Value::Nil).map_err(lua_err)?;
+        }
+
+        let src = std::fs::read_to_string(&self.path).context("Failed to load script")?;
+        // Name the chunk after the file so that errors and inline shaders point at it
+        let chunk_name = format!("@{}", self.path.display());
+        self.budget
+            .call("Script", || {
+                self.lua.load(&src).set_name(&chunk_name)?.eval::<mlua::MultiValue>()
+            })
             .map_err(|e| format_err!("{}", e))?;
 
         // Load functions used repeatedly
@@ -110,70 +599,115 @@ impl LuaModule {
         let reload_fn = globals
             .get::<_, LuaFunction>("reload")
             .map_err(lua_err).context("Requires reload() fn")?;
-        reload_fn.call::<(), ()>(()).map_err(lua_err)?;
+        self.budget
+            .call("reload()", || reload_fn.call::<(), ()>(()))
+            .map_err(lua_err)?;
+
+        // Shaders from shader() calls which the new script no longer makes
+        self.new_data.borrow_mut().free_unused_inline_shaders();
 
         let frame_fn = globals
             .get::<_, LuaFunction>("frame")
             .map_err(lua_err).context("Requires frame() fn")?;
         self.frame_fn = Some(frame_fn);
 
+        // The event function is optional
+        self.event_fn = globals
+            .get::<_, Option<LuaFunction>>("event")
+            .map_err(lua_err).context("event must be a function")?;
+
         Ok(())
     }
 
+    /// Record the outcome of compiling a shader, for `shader_status()`
+    pub fn set_shader_status(&mut self, shader: Shader, status: ShaderStatus) {
+        let mut new_data = self.new_data.borrow_mut();
+        if new_data.shaders.contains_key(shader) {
+            new_data.shader_status.insert(shader, status);
+        }
+    }
+
     /// Dump render updates accumulated during Lua callbacks (such as frame() and reload()).
-    pub fn dump_render_updates(&mut self) -> (Vec<(Mesh, MeshData)>, Vec<(Shader, UniquePipeline)>) {
+    pub fn dump_render_updates(&mut self) -> RenderUpdates {
         let mut new_data = self.new_data.borrow_mut();
-        (
-            std::mem::take(&mut new_data.added_meshes), 
-            std::mem::take(&mut new_data.tracked_shaders)
-        )
+        RenderUpdates {
+            added_meshes: std::mem::take(&mut new_data.added_meshes),
+            tracked_shaders: std::mem::take(&mut new_data.tracked_shaders),
+            freed_meshes: std::mem::take(&mut new_data.freed_meshes),
+            freed_shaders: std::mem::take(&mut new_data.freed_shaders),
+            updated_objects: std::mem::take(&mut new_data.updated_objects),
+            despawned_objects: std::mem::take(&mut new_data.despawned_objects),
+        }
     }
 
-    /// For recoverable script errors
-    fn fail_freeze_frame<E: std::fmt::Display>(&mut self, err: E) -> Result<LuaFrame> {
-        console_print(&format!("Error in frame(), stopping until reload: {:#}", err));
+    /// For recoverable script errors; stops calling into the script until the next reload
+    fn fail_freeze<E: std::fmt::Display>(&mut self, callback: &str, err: E) {
+        console_print(&format!("Error in {}(), stopping until reload: {:#}", callback, err));
         self.frame_fn = None;
+        self.event_fn = None;
+    }
+
+    fn fail_freeze_frame<E: std::fmt::Display>(&mut self, err: E) -> Result<LuaFrame> {
+        self.fail_freeze("frame", err);
         Ok(LuaFrame::default())
     }
 
     /// Run the frame function and build a framepacket
     /// before using this framepacket, you may want to call dump_data() and process the results,
     /// since the next frame may use the data from it
-    pub fn frame(&mut self) -> Result<LuaFrame> {
+    pub fn frame(&mut self, timing: FrameTiming, extent: Extent2D) -> Result<LuaFrame> {
         // If frame fn hasn't been installed yet, do nothing 
         let frame_fn = match self.frame_fn.as_ref() {
             Some(f) => f,
             None => return Ok(LuaFrame::default()),
         };
 
+        // Build the frame context
+        let ctx = frame_context(self.lua, timing, extent).map_err(lua_err)?;
+
         // Call frame function
-        let table = match frame_fn.call::<(), LuaTable>(()) {
+        let table = match self.budget.call("frame()", || frame_fn.call::<_, LuaTable>(ctx)) {
             Err(e) => return self.fail_freeze_frame(e),
             Ok(t) => t,
         };
-        
-        // TODO: This is magic! Magic is exciting! But it is also a huge pain in the bunghole.
-        // Please choose another interface lmao
-        let anim = table.get("anim").unwrap_or(0.0);
-        let cmds = match decode_draw_table(table) {
-            Err(e) => return self.fail_freeze_frame(dbg!(e)),
-            Ok(t) => t,
+
+        let frame = match decode_frame_table(table) {
+            Err(e) => return self.fail_freeze_frame(e),
+            Ok(frame) => frame,
         };
 
-        Ok(LuaFrame {
-            anim,
-            cmds,
-        })
+        let checked = self.new_data.borrow().check_draws(&frame.cmds);
+        match checked {
+            Err(e) => self.fail_freeze_frame(e),
+            Ok(()) => Ok(frame),
+        }
     }
 
-    pub fn event(&mut self, _engine: &mut RenderEngine, _event: &PlatformEvent) -> Result<()> {
+    /// Pass window events on to the script's event() function, if it has one
+    pub fn event(&mut self, _engine: &mut RenderEngine, event: &PlatformEvent) -> Result<()> {
+        // If event fn hasn't been installed yet, do nothing 
+        let event_fn = match self.event_fn.as_ref() {
+            Some(f) => f,
+            None => return Ok(()),
+        };
+
+        let table = match event_to_lua(self.lua, event).map_err(lua_err)? {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+
+        if let Err(e) = self.budget.call("event()", || event_fn.call::<_, ()>(table)) {
+            self.fail_freeze("event", e);
+        }
+
         Ok(())
     }
 }
 
-/// A set of draw commands
+/// A set of draw commands, and the shader parameters to go with them
 pub struct LuaFrame {
     pub cmds: Vec<DrawCmd>,
+    /// Value of `anim` in the shaders' `PerFrame` block
     pub anim: f32,
 }
 
@@ -187,17 +721,49 @@ impl Default for LuaFrame {
 }
 
 impl NewDataLua {
-    pub fn add_mesh(&mut self, vertices: Vec<f32>, indices: Vec<u32>) -> Mesh {
-        let vertices = vertices
-            .chunks_exact(6)
-            .map(|chunk| Vertex {
-                pos: [chunk[0], chunk[1], chunk[2]],
-                color: [chunk[3], chunk[4], chunk[5]],
-            })
-            .collect();
-        let key = self.meshes.insert(());
-        self.added_meshes.push((key, (vertices, indices)));
-        key
+    pub fn add_mesh(&mut self, vertices: Vec<f64>, indices: Vec<u32>, options: MeshOptions) -> Result<Mesh, String> {
+        let args = MeshArgs {
+            function: "add_mesh",
+            vertices: 1,
+            indices: 2,
+        };
+        validate_mesh(&args, &options.layout, &vertices, &indices, options.primitive)?;
+
+        let data = MeshData {
+            vertices: options.layout.pack(&vertices),
+            indices,
+            layout: options.layout.clone(),
+            dynamic: options.dynamic,
+        };
+        let key = self.meshes.insert(MeshInfo {
+            dynamic: options.dynamic,
+            layout: options.layout,
+            primitive: options.primitive,
+            n_indices: data.indices.len(),
+            restart: data.indices.contains(&PRIMITIVE_RESTART),
+        });
+        self.added_meshes.push((key, data));
+        Ok(key)
+    }
+
+    pub fn update_mesh(&mut self, key: Mesh, vertices: Vec<f64>, indices: Vec<u32>) -> Result<(), String> {
+        let info = self.meshes.get_mut(key).ok_or("Mesh does not exist")?;
+        let args = MeshArgs {
+            function: "update_mesh",
+            vertices: 2,
+            indices: 3,
+        };
+        validate_mesh(&args, &info.layout, &vertices, &indices, info.primitive)?;
+        info.n_indices = indices.len();
+        info.restart = indices.contains(&PRIMITIVE_RESTART);
+
+        self.added_meshes.push((key, MeshData {
+            vertices: info.layout.pack(&vertices),
+            indices,
+            layout: info.layout.clone(),
+            dynamic: info.dynamic,
+        }));
+        Ok(())
     }
 
     pub fn track_shader(
@@ -205,26 +771,326 @@ impl NewDataLua {
         vertex_path: String,
         fragment_path: String,
         primitive: String,
+        options: PipelineOptions,
+        defines: Defines,
     ) -> Result<Shader, String> {
-        let primitive = match primitive.to_lowercase().as_str() {
-            "triangles" | "tri" => PrimitiveTopology::TRIANGLE_LIST,
-            "points" => PrimitiveTopology::POINT_LIST,
-            "lines" => PrimitiveTopology::LINE_LIST,
-            _ => return Err(format!("Unrecognized primitive type {}", primitive)),
+        let primitive = parse_shader_primitive(&primitive, &options, self.features)?;
+
+        let key = self.shaders.insert(ShaderInfo {
+            primitive,
+            primitive_restart: options.primitive_restart,
+        });
+
+        let unique_pipeline = UniquePipeline {
+            vertex: ShaderSource::File(vertex_path),
+            fragment: ShaderSource::File(fragment_path),
+            primitive,
+            options,
+            defines,
         };
 
-        let key = self.shaders.insert(());
+        self.push_shader(key, unique_pipeline);
+
+        Ok(key)
+    }
+
+    /// Track a shader built from source strings. Calls from the same place (`site`) return the
+    /// same handle, which is only recompiled if the sources or options have changed. A site may
+    /// only change its sources once per reload, so that calls through a helper function or in a
+    /// loop can't silently replace each other's shaders.
+    pub fn inline_shader(
+        &mut self,
+        site: String,
+        vertex_source: String,
+        fragment_source: String,
+        primitive: String,
+        options: PipelineOptions,
+        defines: Defines,
+    ) -> Result<Shader, String> {
+        let primitive = parse_shader_primitive(&primitive, &options, self.features)?;
 
         let unique_pipeline = UniquePipeline {
-            vertex_path,
-            fragment_path,
+            vertex: ShaderSource::Inline {
+                name: format!("{} (vertex)", site),
+                source: vertex_source,
+            },
+            fragment: ShaderSource::Inline {
+                name: format!("{} (fragment)", site),
+                source: fragment_source,
+            },
             primitive,
+            options,
+            defines,
         };
 
-        self.tracked_shaders.push((key, unique_pipeline));
+        let info = ShaderInfo {
+            primitive,
+            primitive_restart: unique_pipeline.options.primitive_restart,
+        };
+        let shaders = &mut self.shaders;
+        let existing = self
+            .inline_shaders
+            .get(&site)
+            .filter(|(key, _)| shaders.contains_key(*key));
+
+        let key = match existing {
+            Some((key, old)) if *old == unique_pipeline => {
+                self.inline_sites_used.insert(site);
+                return Ok(*key);
+            }
+            Some(_) if self.inline_sites_used.contains(&site) => {
+                return Err(format!(
+                    "shader() at {} was already called with different sources or options; give each shader a distinct `name` in its options",
+                    site
+                ));
+            }
+            Some((key, _)) => {
+                shaders[*key] = info;
+                *key
+            }
+            None => shaders.insert(info),
+        };
+
+        self.push_shader(key, unique_pipeline.clone());
+        self.inline_sites_used.insert(site.clone());
+        self.inline_shaders.insert(site, (key, unique_pipeline));
+
+        Ok(key)
+    }
 
+    /// Check that each draw command refers to live resources, and draws a whole number of
+    /// primitives
+    fn check_draws(&self, cmds: &[DrawCmd]) -> Result<()> {
+        for (idx, cmd) in cmds.iter().enumerate() {
+            self.check_draw(cmd)
+                .map_err(|e| format_err!("draw[{}]: {}", idx + 1, e))?;
+        }
+        Ok(())
+    }
+
+    fn check_draw(&self, cmd: &DrawCmd) -> Result<(), String> {
+        let shader = self.shaders.get(cmd.shader).ok_or("shader was freed")?;
+
+        let n_indices = match cmd.geometry {
+            DrawGeometry::Mesh { mesh, max_idx } => {
+                let info = self.meshes.get(mesh).ok_or("mesh was freed")?;
+                if info.restart && !shader.primitive_restart {
+                    return Err("the mesh contains PRIMITIVE_RESTART, but the shader wasn't tracked with `restart = true`".into());
+                }
+                match max_idx {
+                    Some(max) => (max as usize).min(info.n_indices),
+                    None => info.n_indices,
+                }
+            }
+            DrawGeometry::Procedural { n_verts } => n_verts as usize,
+        };
+
+        check_index_count(n_indices, shader.primitive)
+    }
+
+    /// Decode and check the description of a scene object; the same fields as a draw command
+    fn decode_object(&self, desc: &LuaTable) -> LuaResult<DrawCmd> {
+        let cmd = decode_draw_cmd(desc.clone())
+            .map_err(|e| LuaError::external(format!("{:#}", e)))?;
+        self.check_draw(&cmd).map_err(LuaError::external)?;
+        Ok(cmd)
+    }
+
+    pub fn spawn(&mut self, lua: &Lua, desc: LuaTable) -> LuaResult<Object> {
+        // Keep our own copy of the description, for later updates to be merged into
+        let desc = copy_table(lua, &desc)?;
+        let cmd = self.decode_object(&desc)?;
+        let key = self.objects.insert(lua.create_registry_value(desc)?);
+        self.updated_objects.push((key, cmd));
         Ok(key)
     }
+
+    /// Merge fields into an object's description. The object is left unchanged if the result is
+    /// invalid.
+    pub fn update_object(&mut self, lua: &Lua, key: Object, fields: LuaTable) -> LuaResult<()> {
+        let stored = self
+            .objects
+            .get(key)
+            .ok_or_else(|| LuaError::external("Object does not exist or was despawned"))?;
+        let desc = copy_table(lua, &lua.registry_value::<LuaTable>(stored)?)?;
+        for pair in fields.pairs::<LuaValue, LuaValue>() {
+            let (k, v) = pair?;
+            desc.set(k, v)?;
+        }
+
+        let cmd = self.decode_object(&desc)?;
+        self.objects[key] = lua.create_registry_value(desc)?;
+        lua.expire_registry_values();
+        self.updated_objects.push((key, cmd));
+        Ok(())
+    }
+
+    pub fn despawn(&mut self, lua: &Lua, key: Object) -> Result<(), String> {
+        self.objects.remove(key).ok_or("Object does not exist or was already despawned")?;
+        lua.expire_registry_values();
+        self.despawned_objects.push(key);
+        Ok(())
+    }
+
+    pub fn free_mesh(&mut self, key: Mesh) -> Result<(), String> {
+        self.meshes.remove(key).ok_or("Mesh does not exist or was already freed")?;
+        self.freed_meshes.push(key);
+        Ok(())
+    }
+
+    /// Queue a shader to be compiled
+    fn push_shader(&mut self, key: Shader, unique_pipeline: UniquePipeline) {
+        self.tracked_shaders.push((key, unique_pipeline));
+        self.shader_status.insert(key, ShaderStatus::Pending);
+    }
+
+    /// Describe the outcome of a shader's latest compilation as
+    /// `{ status = "pending" | "compiled" | "failed", errors = { { file, line, message }, ... } }`
+    pub fn shader_status<'lua>(&self, lua: &'lua Lua, key: Shader) -> LuaResult<LuaTable<'lua>> {
+        let status = self
+            .shader_status
+            .get(key)
+            .filter(|_| self.shaders.contains_key(key))
+            .ok_or_else(|| LuaError::external("Shader does not exist or was already freed"))?;
+
+        let table = lua.create_table()?;
+        let errors = lua.create_table()?;
+        let name = match status {
+            ShaderStatus::Pending => "pending",
+            ShaderStatus::Compiled => "compiled",
+            ShaderStatus::Failed(diagnostics) => {
+                for (idx, diagnostic) in diagnostics.iter().enumerate() {
+                    let error = lua.create_table()?;
+                    error.set("file", diagnostic.file.as_str())?;
+                    error.set("line", diagnostic.line)?;
+                    error.set("message", diagnostic.message.as_str())?;
+                    errors.set(idx + 1, error)?;
+                }
+                "failed"
+            }
+        };
+        table.set("status", name)?;
+        table.set("errors", errors)?;
+        Ok(table)
+    }
+
+    pub fn free_shader(&mut self, key: Shader) -> Result<(), String> {
+        self.shaders.remove(key).ok_or("Shader does not exist or was already freed")?;
+        self.shader_status.remove(key);
+        self.inline_shaders.retain(|_, (shader, _)| *shader != key);
+        self.freed_shaders.push(key);
+        Ok(())
+    }
+
+    /// Free the shaders of call sites which haven't called shader() since the last reload began
+    fn free_unused_inline_shaders(&mut self) {
+        let used = &self.inline_sites_used;
+        let unused: Vec<Shader> = self
+            .inline_shaders
+            .iter()
+            .filter(|(site, _)| !used.contains(*site))
+            .map(|(_, (key, _))| *key)
+            .collect();
+
+        for key in unused {
+            // Shaders freed by the script are no longer listed, so this can't fail
+            let _ = self.free_shader(key);
+        }
+    }
+}
+
+/// Check a primitive name against the options it's used with
+fn parse_shader_primitive(
+    primitive: &str,
+    options: &PipelineOptions,
+    features: DeviceFeatures,
+) -> Result<PrimitiveTopology, String> {
+    let primitive = parse_primitive(primitive, features)?;
+    if options.primitive_restart && !is_strip(primitive) {
+        return Err("Primitive restart requires a strip or fan primitive".into());
+    }
+    Ok(primitive)
+}
+
+/// Registry key of `debug.getinfo`, used by call_site()
+const GETINFO_KEY: &str = "micro_engine.getinfo";
+
+/// Keep `debug.getinfo` where only Rust can reach it, and remove the rest of the debug library,
+/// which would let scripts break out of the time budget and tamper with the engine's functions
+fn hide_debug_library(lua: &Lua) -> LuaResult<()> {
+    let globals = lua.globals();
+    let debug: LuaTable = globals.get("debug")?;
+    let getinfo: LuaFunction = debug.get("getinfo")?;
+    lua.set_named_registry_value(GETINFO_KEY, getinfo)?;
+
+    globals.set("debug", LuaValue::Nil)?;
+    let loaded: LuaTable = globals.get::<_, LuaTable>("package")?.get("loaded")?;
+    loaded.set("debug", LuaValue::Nil)
+}
+
+/// Where the Lua function calling into Rust was called from, as `file:line`
+fn call_site(lua: &Lua) -> LuaResult<String> {
+    // Level 1 is the Rust function itself, and level 2 its caller
+    let getinfo: LuaFunction = lua.named_registry_value(GETINFO_KEY)?;
+    let info: Option<LuaTable> = getinfo.call((2, "Sl"))?;
+
+    Ok(match info {
+        Some(info) => format!(
+            "{}:{}",
+            info.get::<_, String>("short_src")?,
+            info.get::<_, i64>("currentline")?
+        ),
+        None => "inline shader".to_string(),
+    })
+}
+
+/// Shallow copy of a table
+fn copy_table<'lua>(lua: &'lua Lua, table: &LuaTable<'lua>) -> LuaResult<LuaTable<'lua>> {
+    let copy = lua.create_table()?;
+    for pair in table.clone().pairs::<LuaValue, LuaValue>() {
+        let (k, v) = pair?;
+        copy.set(k, v)?;
+    }
+    Ok(copy)
+}
+
+/// Context passed to the script's frame() function
+fn frame_context(lua: &Lua, timing: FrameTiming, extent: Extent2D) -> LuaResult<LuaTable> {
+    let ctx = lua.create_table()?;
+    ctx.set("time", timing.time)?;
+    ctx.set("dt", timing.delta_time)?;
+    ctx.set("frame", timing.frame)?;
+    ctx.set("width", extent.width)?;
+    ctx.set("height", extent.height)?;
+    Ok(ctx)
+}
+
+/// Decode the table returned from frame(); `{ draw = { ... }, params = { ... } }`
+fn decode_frame_table(table: Table<'_>) -> Result<LuaFrame> {
+    let draw: LuaTable = table
+        .get("draw")
+        .map_err(lua_err)
+        .context("frame() must return a table with a `draw` list")?;
+    let cmds = decode_draw_table(draw)?;
+
+    let mut frame = LuaFrame {
+        cmds,
+        ..LuaFrame::default()
+    };
+
+    let params: Option<LuaTable> = table
+        .get("params")
+        .map_err(lua_err)
+        .context("`params` must be a table")?;
+    if let Some(params) = params {
+        frame.anim = params
+            .get::<_, Option<f32>>("anim")
+            .map_err(lua_err)
+            .context("`params.anim` must be a number")?
+            .unwrap_or(0.);
+    }
+
+    Ok(frame)
 }
 
 fn decode_draw_table(table: Table<'_>) -> Result<Vec<DrawCmd>> {
@@ -240,20 +1106,103 @@ fn decode_draw_table(table: Table<'_>) -> Result<Vec<DrawCmd>> {
     Ok(cmds)
 }
 
-fn decode_draw_cmd(table: Table<'_>) -> Result<DrawCmd> {
-    // Optionally get the transform matrix
-    let transform = match table.get::<_, Vec<f32>>("trans") {
-        //Err(e) => return self.fail_freeze_frame(format!("Transform matrix is not a flat array; {}", e)),
-        Err(_) => None,
-        Ok(in_trans) => {
+/// Decode a transform, either as a mat4 or a flat column-major array
+fn decode_transform(value: LuaValue) -> Result<Transform> {
+    match value {
+        LuaValue::UserData(ud) => match ud.borrow::<LuaMat4>() {
+            Ok(m) => Ok(Transform::from(*m)),
+            Err(_) => bail!("Transform is userdata, but not a mat4"),
+        },
+        LuaValue::Table(in_trans) => {
+            let in_trans = in_trans
+                .sequence_values::<f32>()
+                .collect::<LuaResult<Vec<f32>>>()
+                .map_err(lua_err)
+                .context("Transform matrix is not a flat array")?;
             let mut transform: Transform = [[0.0f32; 4]; 4];
             for (i, o) in in_trans.chunks_exact(4).zip(transform.iter_mut()) {
                 o.copy_from_slice(&i[..]);
             }
-            Some(transform)
-        },
+            Ok(transform)
+        }
+        other => bail!("Transform must be a mat4 or a flat array, got {}", other.type_name()),
+    }
+}
+
+/// Decode the `instances` list of a draw command, if it has one
+fn decode_instances(table: &Table<'_>) -> Result<Option<Vec<Transform>>> {
+    let instances: Option<LuaTable> = table
+        .get("instances")
+        .map_err(lua_err)
+        .context("`instances` must be a list of transforms")?;
+    let list = match instances {
+        Some(list) => list,
+        None => return Ok(None),
     };
 
+    let transforms = list
+        .sequence_values::<LuaValue>()
+        .enumerate()
+        .map(|(idx, value)| {
+            decode_transform(value.map_err(lua_err)?)
+                .with_context(|| format!("instances[{}]", idx + 1))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Some(transforms))
+}
+
+/// Decode the `instance_data` and `instance_layout` fields of a draw command
+fn decode_instance_data(table: &Table<'_>, n_instances: usize) -> Result<Option<InstanceData>> {
+    let data: Option<Vec<f64>> = table
+        .get("instance_data")
+        .map_err(lua_err)
+        .context("`instance_data` must be a list of numbers")?;
+    let data = match data {
+        Some(d) => d,
+        None => return Ok(None),
+    };
+
+    let layout: LuaTable = table
+        .get("instance_layout")
+        .map_err(lua_err)
+        .context("`instance_data` requires an `instance_layout`")?;
+    let layout = decode_vertex_layout(layout).map_err(lua_err)?;
+
+    let expected = n_instances * layout.components_per_vertex();
+    if data.len() != expected {
+        bail!(
+            "`instance_data` has {} values, but {} instance(s) of ({}) take {}",
+            data.len(),
+            n_instances,
+            layout.describe(),
+            expected
+        );
+    }
+    check_integer_attributes(&layout, &data)
+        .map_err(|e| format_err!("`instance_data`: {}", e))?;
+
+    let data = layout.pack(&data);
+    Ok(Some(InstanceData { layout, data }))
+}
+
+fn decode_draw_cmd(table: Table<'_>) -> Result<DrawCmd> {
+    // Either a single optional transform, or one for each instance
+    let transform = match table.get::<_, LuaValue>("trans").map_err(lua_err)? {
+        LuaValue::Nil => None,
+        value => Some(decode_transform(value)?),
+    };
+
+    let transforms = match (transform, decode_instances(&table)?) {
+        (Some(_), Some(_)) => bail!("DrawCmd has both `trans` and `instances`"),
+        (Some(transform), None) => Some(vec![transform]),
+        (None, instances) => instances,
+    };
+
+    // A command without transforms is still drawn once, but an empty `instances` list isn't drawn
+    let n_instances = transforms.as_ref().map_or(1, |t| t.len());
+    let instance_data = decode_instance_data(&table, n_instances)?;
+
     // Read mesh id from the table
     let mesh: Option<Mesh> = table.get("mesh").ok();
     let n_indices: Option<u32> = table.get("n_indices").ok();
@@ -278,6 +1227,7 @@ fn decode_draw_cmd(table: Table<'_>) -> Result<DrawCmd> {
     Ok(DrawCmd {
         shader,
         geometry,
-        transform,
+        transforms,
+        instance_data,
     })
 }
@@ -1,9 +1,19 @@
 mod console;
 mod engine;
 mod file_watcher;
+mod input;
+mod lua_math;
 mod lua_module;
 mod main_loop;
+mod mesh_validation;
+mod pipeline;
+mod pipeline_cache;
+mod reflection;
+mod script_budget;
+mod script_deps;
 mod shader_update_calc;
+mod spirv_cache;
+mod vertex_layout;
 use anyhow::Result;
 use main_loop::Main;
 use watertender::app_info::AppInfo;
@@ -1,16 +1,20 @@
-use crate::console::{console as run_console, print_lua_ret, ConsoleMsg};
-use crate::engine::{FramePacket, RenderEngine};
+use crate::console::{console as run_console, console_print, print_lua_ret, ConsoleMsg};
+use crate::engine::{FrameClock, FramePacket, RenderEngine};
 use crate::file_watcher::watch;
+use crate::input::InputState;
 use crate::lua_module::LuaModule;
+use crate::script_budget::Budget;
 use anyhow::{Context, Result, format_err};
 use std::path::PathBuf;
 use std::sync::mpsc::{self, Receiver};
 use watertender::prelude::*;
-use crate::shader_update_calc::{ShaderUpdateCalculator, compile_jobs};
-use shaderc::Compiler;
+use crate::shader_update_calc::{CompileWorker, ShaderUpdateCalculator};
+use crate::spirv_cache::SpirvCache;
 use midir::{MidiInput, MidiInputConnection, MidiInputPort};
 use std::io::{stdin, stdout, Write};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{cell::RefCell, rc::Rc};
 
 pub struct MidiUpdate {
     pub stamp: u64,
@@ -22,13 +26,15 @@ type MidiUpdates = Arc<Mutex<Vec<MidiUpdate>>>;
 pub struct Main {
     engine: RenderEngine,
     lua_module: LuaModule,
+    input: Rc<RefCell<InputState>>,
     console: Receiver<ConsoleMsg>,
     file_watcher: Receiver<PathBuf>,
     shader_update_calc: ShaderUpdateCalculator,
     midi_updates: MidiUpdates,
     _midi_conn: Option<MidiInputConnection<()>>,
     midi_vals: [u32; 3],
-    compiler: Compiler,
+    compile_worker: CompileWorker,
+    clock: FrameClock,
 }
 
 // TODO: This should be done somehow else. Maybe a menu?
@@ -78,8 +84,14 @@ impl MainLoop for Main {
             None
         };
 
-        let engine = RenderEngine::new(core, platform)?;
-        let lua_module = LuaModule::new(PathBuf::from(&lua_path))?;
+        let mut engine = RenderEngine::new(core, platform)?;
+        let input = Rc::new(RefCell::new(InputState::default()));
+        let lua_module = LuaModule::new(
+            PathBuf::from(&lua_path),
+            input.clone(),
+            Budget::default(),
+            engine.features(),
+        )?;
 
         let (console_tx, console) = mpsc::channel();
         std::thread::spawn(move || run_console(console_tx));
@@ -87,11 +99,12 @@ impl MainLoop for Main {
         let (watcher_tx, watcher) = mpsc::channel();
         std::thread::spawn(move || watch(watch_path, watcher_tx));
 
-        let compiler = Compiler::new().context("Failed to init shaderc")?;
+        let compile_worker = CompileWorker::new(SpirvCache::new(), &mut engine)?;
         let shader_update_calc = ShaderUpdateCalculator::new();
 
         let mut instance = Self {
-            compiler,
+            compile_worker,
+            clock: FrameClock::new(),
             _midi_conn: midi_conn,
             midi_vals: [0; 3],
             midi_updates,
@@ -100,6 +113,7 @@ impl MainLoop for Main {
             console,
             engine,
             lua_module,
+            input,
         };
 
         // Handle new shaders and meshes
@@ -114,15 +128,45 @@ impl MainLoop for Main {
         core: &SharedCore,
         mut platform: Platform<'_>,
     ) -> Result<PlatformReturn> {
+        let timing = self.clock.tick();
         let mut do_lua_reload = false;
 
         // Receive messages from the console
         for msg in self.console.try_iter() {
             match msg {
                 ConsoleMsg::Command(s) => {
-                    print_lua_ret(self.lua_module.lua.load(&s).eval());
+                    print_lua_ret(self.lua_module.eval(&s));
+                }
+                ConsoleMsg::Budget(Some((soft, hard))) => {
+                    self.lua_module.set_budget(Budget {
+                        soft: Duration::from_millis(soft),
+                        hard: Duration::from_millis(hard),
+                    });
+                }
+                ConsoleMsg::Deps => {
+                    let deps = self.lua_module.dependencies();
+                    let list = deps
+                        .iter()
+                        .map(|p| format!("\t{}", p.display()))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    console_print(&format!("Script dependencies:\n{}", list));
+                }
+                ConsoleMsg::Stats => {
+                    console_print(&format!("{}", self.engine.stats()));
+                }
+                ConsoleMsg::Budget(None) => {
+                    let budget = self.lua_module.budget();
+                    console_print(&format!(
+                        "Usage: /budget <soft ms> <hard ms>; currently soft {:?}, hard {:?}",
+                        budget.soft, budget.hard
+                    ));
+                }
+                ConsoleMsg::Reset => {
+                    self.lua_module.reset()?;
+                    // The new script can't refer to the old one's objects
+                    self.engine.clear_objects();
                 }
-                ConsoleMsg::Reset => self.lua_module.reset()?,
                 ConsoleMsg::Exit => platform.request_exit(),
                 ConsoleMsg::Reload => do_lua_reload = true,
             }
@@ -130,13 +174,13 @@ impl MainLoop for Main {
 
         // Receive messages from the file watcher
         for path in self.file_watcher.try_iter() {
-            match path.extension().and_then(|s| s.to_str()) {
-                Some("lua") => do_lua_reload = true,
-                Some("frag" | "vert") => {
-                    self.shader_update_calc.shader_file_touched(&path.canonicalize()?);
-                    // TODO: else error? It would happen somewhat often...
-                }
-                _ => (),
+            if self.lua_module.depends_on(&path) {
+                do_lua_reload = true;
+            }
+
+            // Shaders may include files with any extension, so let the calculator decide
+            if let Ok(path) = path.canonicalize() {
+                self.shader_update_calc.shader_file_touched(&path);
             }
         }
 
@@ -149,7 +193,9 @@ impl MainLoop for Main {
         self.handle_lua_updates()?;
 
         // Get render packet
-        let lua_frame = self.lua_module.frame()?;
+        let lua_frame = self.lua_module.frame(timing, self.engine.extent())?;
+        let [mouse_x, mouse_y] = self.input.borrow().mouse_pos();
+        self.input.borrow_mut().end_frame();
 
         // Get latest midi frame
         let mut midi_updates: Vec<MidiUpdate> = std::mem::take(self.midi_updates.lock().unwrap().as_mut());
@@ -164,6 +210,8 @@ impl MainLoop for Main {
             cmds: lua_frame.cmds,
             anim: lua_frame.anim,
             midi: self.midi_vals,
+            timing,
+            mouse: [mouse_x as f32, mouse_y as f32],
         };
 
         // Render
@@ -180,6 +228,7 @@ impl MainLoop for Main {
         core: &Core,
         platform: Platform<'_>,
     ) -> Result<()> {
+        self.input.borrow_mut().handle_event(&event);
         self.lua_module.event(&mut self.engine, &event)?;
         self.engine.event(event, core, platform)
     }
@@ -187,16 +236,42 @@ impl MainLoop for Main {
 
 impl Main {
     pub fn handle_lua_updates(&mut self) -> Result<()> {
-        let (added_meshes, tracked_shaders) = self.lua_module.dump_render_updates();
-        for (shader, unique) in tracked_shaders {
-            self.shader_update_calc.track_shader(shader, unique)?;
+        let updates = self.lua_module.dump_render_updates();
+        for (shader, unique) in updates.tracked_shaders {
+            self.shader_update_calc.track_shader(shader, unique);
+        }
+
+        for &shader in &updates.freed_shaders {
+            self.shader_update_calc.untrack_shader(shader);
+            self.compile_worker.forget(shader);
         }
 
-        let updates = self.shader_update_calc.updates();
-        compile_jobs(&mut self.compiler, &updates, &mut self.engine)?;
+        // Shaders are compiled in the background; until then the previous version is drawn
+        let jobs = self.shader_update_calc.updates();
+        self.compile_worker.submit(jobs)?;
+        for result in self.compile_worker.finished(&mut self.engine)? {
+            self.shader_update_calc.set_includes(result.shader, result.includes);
+            self.lua_module.set_shader_status(result.shader, result.status);
+        }
+
+        for (mesh, data) in updates.added_meshes {
+            self.engine.add_mesh(&data, mesh)?;
+        }
+
+        for (object, cmd) in updates.updated_objects {
+            self.engine.set_object(object, cmd);
+        }
+
+        for object in updates.despawned_objects {
+            self.engine.remove_object(object);
+        }
+
+        for mesh in updates.freed_meshes {
+            self.engine.free_mesh(mesh);
+        }
 
-        for (mesh, (verts, indices)) in added_meshes {
-            self.engine.add_mesh(&verts, &indices, mesh)?;
+        for shader in updates.freed_shaders {
+            self.engine.free_shader(shader);
         }
 
         Ok(())
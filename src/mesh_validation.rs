@@ -0,0 +1,349 @@
+use crate::pipeline::DeviceFeatures;
+use crate::vertex_layout::{AttributeFormat, VertexLayout};
+use watertender::vk::PrimitiveTopology;
+
+/// Where the mesh data came from, so that errors can point at the offending argument
+pub struct MeshArgs {
+    /// Name of the Lua function
+    pub function: &'static str,
+    /// Argument number of the vertex list
+    pub vertices: usize,
+    /// Argument number of the index list
+    pub indices: usize,
+}
+
+impl MeshArgs {
+    fn error(&self, arg: usize, msg: String) -> String {
+        format!("bad argument #{} to '{}' ({})", arg, self.function, msg)
+    }
+}
+
+/// Index which starts a new strip or fan, when primitive restart is enabled
+pub const PRIMITIVE_RESTART: u32 = u32::MAX;
+
+/// Parse the primitive names accepted by the scripts. Primitives with adjacency need the
+/// geometryShader device feature.
+pub fn parse_primitive(name: &str, features: DeviceFeatures) -> Result<PrimitiveTopology, String> {
+    let primitive = match name.to_lowercase().as_str() {
+        "triangles" | "tri" => Ok(PrimitiveTopology::TRIANGLE_LIST),
+        "points" => Ok(PrimitiveTopology::POINT_LIST),
+        "lines" => Ok(PrimitiveTopology::LINE_LIST),
+        "line_strip" => Ok(PrimitiveTopology::LINE_STRIP),
+        "triangle_strip" | "tri_strip" => Ok(PrimitiveTopology::TRIANGLE_STRIP),
+        "triangle_fan" | "tri_fan" => Ok(PrimitiveTopology::TRIANGLE_FAN),
+        "lines_adjacency" => Ok(PrimitiveTopology::LINE_LIST_WITH_ADJACENCY),
+        "line_strip_adjacency" => Ok(PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY),
+        "triangles_adjacency" => Ok(PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY),
+        "triangle_strip_adjacency" => Ok(PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY),
+        _ => Err(format!("Unrecognized primitive type {}", name)),
+    }?;
+
+    if is_adjacency(primitive) && !features.geometry_shader {
+        return Err(format!(
+            "Primitive type {} needs the geometryShader device feature, which isn't enabled",
+            name
+        ));
+    }
+    Ok(primitive)
+}
+
+fn is_adjacency(primitive: PrimitiveTopology) -> bool {
+    matches!(
+        primitive,
+        PrimitiveTopology::LINE_LIST_WITH_ADJACENCY
+            | PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+            | PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY
+            | PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+    )
+}
+
+/// Whether the primitive is a strip or fan, and so may use primitive restart
+pub fn is_strip(primitive: PrimitiveTopology) -> bool {
+    matches!(
+        primitive,
+        PrimitiveTopology::LINE_STRIP
+            | PrimitiveTopology::TRIANGLE_STRIP
+            | PrimitiveTopology::TRIANGLE_FAN
+            | PrimitiveTopology::LINE_STRIP_WITH_ADJACENCY
+            | PrimitiveTopology::TRIANGLE_STRIP_WITH_ADJACENCY
+    )
+}
+
+/// Number of indices making up each primitive of a list; None for strips and fans, which may
+/// have any number
+fn indices_per_primitive(primitive: PrimitiveTopology) -> Option<usize> {
+    match primitive {
+        PrimitiveTopology::POINT_LIST => Some(1),
+        PrimitiveTopology::LINE_LIST => Some(2),
+        PrimitiveTopology::TRIANGLE_LIST => Some(3),
+        PrimitiveTopology::LINE_LIST_WITH_ADJACENCY => Some(4),
+        PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY => Some(6),
+        _ => None,
+    }
+}
+
+/// Human readable name of a primitive type
+fn primitive_name(primitive: PrimitiveTopology) -> &'static str {
+    match primitive {
+        PrimitiveTopology::TRIANGLE_LIST => "triangles",
+        PrimitiveTopology::LINE_LIST => "lines",
+        PrimitiveTopology::POINT_LIST => "points",
+        PrimitiveTopology::LINE_LIST_WITH_ADJACENCY => "lines with adjacency",
+        PrimitiveTopology::TRIANGLE_LIST_WITH_ADJACENCY => "triangles with adjacency",
+        _ => "unknown primitive",
+    }
+}
+
+/// Check that an index count makes up a whole number of primitives
+pub fn check_index_count(n_indices: usize, primitive: PrimitiveTopology) -> Result<(), String> {
+    let per_primitive = match indices_per_primitive(primitive) {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+
+    if n_indices % per_primitive != 0 {
+        return Err(format!(
+            "{} indices do not make up a whole number of {} ({} indices each)",
+            n_indices,
+            primitive_name(primitive),
+            per_primitive
+        ));
+    }
+    Ok(())
+}
+
+/// Check that the values of integer attributes are whole numbers which fit the attribute's
+/// format; packing would otherwise round or saturate them
+pub fn check_integer_attributes(layout: &VertexLayout, values: &[f64]) -> Result<(), String> {
+    let per_vertex = layout.components_per_vertex();
+    if per_vertex == 0 {
+        return Ok(());
+    }
+
+    for (vertex_idx, vertex) in values.chunks_exact(per_vertex).enumerate() {
+        let mut offset = 0;
+        for attr in &layout.attributes {
+            let components = attr.components as usize;
+            let range = match attr.format {
+                AttributeFormat::F32 => None,
+                AttributeFormat::I32 => Some((i32::MIN as f64, i32::MAX as f64)),
+                AttributeFormat::U32 => Some((0.0, u32::MAX as f64)),
+            };
+
+            if let Some((min, max)) = range {
+                let attr_values = &vertex[offset..offset + components];
+                // NaN and infinities have no whole part, so they fail the first test
+                let bad = attr_values
+                    .iter()
+                    .position(|&v| v.fract() != 0.0 || v < min || v > max);
+                if let Some(bad) = bad {
+                    return Err(format!(
+                        "element {} is {}, in attribute \"{}\" of vertex {}; {} values must be whole numbers from {} to {}",
+                        vertex_idx * per_vertex + offset + bad + 1,
+                        attr_values[bad],
+                        attr.name,
+                        vertex_idx + 1,
+                        attr.format.name(),
+                        min,
+                        max
+                    ));
+                }
+            }
+
+            offset += components;
+        }
+    }
+
+    Ok(())
+}
+
+/// Check vertex and index lists from a script before they are packed and sent to the GPU
+pub fn validate_mesh(
+    args: &MeshArgs,
+    layout: &VertexLayout,
+    vertices: &[f64],
+    indices: &[u32],
+    primitive: Option<PrimitiveTopology>,
+) -> Result<(), String> {
+    // Vulkan buffers may not be empty
+    if vertices.is_empty() {
+        return Err(args.error(args.vertices, "a mesh needs at least one vertex".into()));
+    }
+    if indices.is_empty() {
+        return Err(args.error(args.indices, "a mesh needs at least one index".into()));
+    }
+
+    let per_vertex = layout.components_per_vertex();
+    if vertices.len() % per_vertex != 0 {
+        return Err(args.error(
+            args.vertices,
+            format!(
+                "{} values is not a whole number of vertices; each vertex has {} values ({})",
+                vertices.len(),
+                per_vertex,
+                layout.describe()
+            ),
+        ));
+    }
+    let n_vertices = vertices.len() / per_vertex;
+
+    if let Some((offset, components, name)) = layout.position() {
+        for (vertex_idx, vertex) in vertices.chunks_exact(per_vertex).enumerate() {
+            let position = &vertex[offset..offset + components];
+            if let Some(bad) = position.iter().position(|v| !v.is_finite()) {
+                return Err(args.error(
+                    args.vertices,
+                    format!(
+                        "element {} is {}, in attribute \"{}\" of vertex {}",
+                        vertex_idx * per_vertex + offset + bad + 1,
+                        position[bad],
+                        name,
+                        vertex_idx + 1
+                    ),
+                ));
+            }
+        }
+    }
+
+    check_integer_attributes(layout, vertices).map_err(|e| args.error(args.vertices, e))?;
+
+    // Restart indices would be read as vertex 0xFFFFFFFF unless drawn as strips with restart on
+    let restart_allowed = primitive.map_or(false, is_strip);
+    if let Some(idx) = indices.iter().position(|&i| i == PRIMITIVE_RESTART && !restart_allowed) {
+        return Err(args.error(
+            args.indices,
+            format!(
+                "element {} is PRIMITIVE_RESTART, which is only allowed in meshes whose `primitive` is a strip or fan",
+                idx + 1
+            ),
+        ));
+    }
+
+    if let Some((idx, &index)) = indices
+        .iter()
+        .enumerate()
+        .find(|(_, &i)| i != PRIMITIVE_RESTART && i as usize >= n_vertices)
+    {
+        return Err(args.error(
+            args.indices,
+            format!(
+                "element {} is {}, but there are only {} vertices (indices start at 0)",
+                idx + 1,
+                index,
+                n_vertices
+            ),
+        ));
+    }
+
+    if let Some(primitive) = primitive {
+        check_index_count(indices.len(), primitive).map_err(|e| args.error(args.indices, e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vertex_layout::VertexAttribute;
+
+    const ARGS: MeshArgs = MeshArgs {
+        function: "add_mesh",
+        vertices: 1,
+        indices: 2,
+    };
+
+    /// A 2D position followed by an integer id of the given format
+    fn id_layout(format: AttributeFormat) -> VertexLayout {
+        VertexLayout {
+            attributes: vec![
+                VertexAttribute {
+                    name: "pos".into(),
+                    components: 2,
+                    format: AttributeFormat::F32,
+                },
+                VertexAttribute {
+                    name: "id".into(),
+                    components: 1,
+                    format,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn index_count() {
+        assert!(check_index_count(6, PrimitiveTopology::TRIANGLE_LIST).is_ok());
+        assert!(check_index_count(4, PrimitiveTopology::TRIANGLE_LIST).is_err());
+        assert!(check_index_count(3, PrimitiveTopology::LINE_LIST).is_err());
+        // Strips may have any number of indices
+        assert!(check_index_count(5, PrimitiveTopology::TRIANGLE_STRIP).is_ok());
+    }
+
+    #[test]
+    fn valid_mesh() {
+        let layout = VertexLayout::pos_color();
+        let vertices = [0.0; 18];
+        let result = validate_mesh(&ARGS, &layout, &vertices, &[0, 1, 2], None);
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn bad_vertex_count_and_indices() {
+        let layout = VertexLayout::pos_color();
+        let vertices = [0.0; 18];
+
+        assert!(validate_mesh(&ARGS, &layout, &[], &[0], None).is_err());
+        assert!(validate_mesh(&ARGS, &layout, &vertices, &[], None).is_err());
+        assert!(validate_mesh(&ARGS, &layout, &vertices[..17], &[0, 1, 2], None).is_err());
+
+        let err = validate_mesh(&ARGS, &layout, &vertices, &[0, 1, 3], None).unwrap_err();
+        assert!(err.starts_with("bad argument #2 to 'add_mesh' (element 3 is 3"), "{}", err);
+
+        let triangles = Some(PrimitiveTopology::TRIANGLE_LIST);
+        assert!(validate_mesh(&ARGS, &layout, &vertices, &[0, 1], triangles).is_err());
+    }
+
+    #[test]
+    fn non_finite_position() {
+        let layout = VertexLayout::pos_color();
+        let mut vertices = [0.0; 12];
+        vertices[7] = f64::NAN;
+
+        let err = validate_mesh(&ARGS, &layout, &vertices, &[0, 1], None).unwrap_err();
+        assert!(err.contains("element 8 is NaN, in attribute \"pos\" of vertex 2"), "{}", err);
+    }
+
+    #[test]
+    fn primitive_restart() {
+        let layout = VertexLayout::pos_color();
+        let vertices = [0.0; 18];
+        let indices = [0, 1, PRIMITIVE_RESTART, 1, 2];
+
+        let strip = Some(PrimitiveTopology::LINE_STRIP);
+        assert!(validate_mesh(&ARGS, &layout, &vertices, &indices, strip).is_ok());
+        assert!(validate_mesh(&ARGS, &layout, &vertices, &indices, None).is_err());
+        let lines = Some(PrimitiveTopology::LINE_LIST);
+        assert!(validate_mesh(&ARGS, &layout, &vertices, &indices, lines).is_err());
+    }
+
+    #[test]
+    fn integer_attributes() {
+        let unsigned = id_layout(AttributeFormat::U32);
+        let signed = id_layout(AttributeFormat::I32);
+
+        assert!(validate_mesh(&ARGS, &unsigned, &[0.0, 0.0, 7.0], &[0], None).is_ok());
+        assert!(validate_mesh(&ARGS, &signed, &[0.0, 0.0, -1.0], &[0], None).is_ok());
+
+        let err = validate_mesh(&ARGS, &unsigned, &[0.0, 0.0, -1.0], &[0], None).unwrap_err();
+        assert!(err.contains("element 3 is -1, in attribute \"id\" of vertex 1"), "{}", err);
+
+        let fractional = [0.0, 0.0, 1.0, 0.0, 0.0, 2.5];
+        let err = validate_mesh(&ARGS, &signed, &fractional, &[0, 1], None).unwrap_err();
+        assert!(err.contains("element 6 is 2.5, in attribute \"id\" of vertex 2"), "{}", err);
+
+        let too_big = [0.0, 0.0, 4294967296.0];
+        assert!(validate_mesh(&ARGS, &unsigned, &too_big, &[0], None).is_err());
+        assert!(check_integer_attributes(&signed, &[0.0, 0.0, f64::INFINITY]).is_err());
+    }
+}
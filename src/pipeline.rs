@@ -0,0 +1,296 @@
+use crate::vertex_layout::VertexLayout;
+use anyhow::{ensure, Result};
+use std::ffi::CString;
+use std::hash::{Hash, Hasher};
+use watertender::prelude::*;
+
+/// Options given to track_shader() which affect how a shader's pipelines are built and drawn
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipelineOptions {
+    /// Draw in submission order after everything else, instead of sorted to save binds
+    pub ordered: bool,
+    pub blend: BlendMode,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    pub cull_mode: vk::CullModeFlags,
+    pub front_face: vk::FrontFace,
+    pub polygon_mode: vk::PolygonMode,
+    /// Widths other than 1.0 require the wideLines device feature
+    pub line_width: f32,
+    pub depth_bias: Option<DepthBias>,
+    /// Start a new strip or fan at each `PRIMITIVE_RESTART` index
+    pub primitive_restart: bool,
+}
+
+/// Optional device features which pipeline options depend on, as enabled on the device
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DeviceFeatures {
+    /// Line widths other than 1.0
+    pub wide_lines: bool,
+    /// Polygon modes other than fill
+    pub fill_mode_non_solid: bool,
+    /// Primitives with adjacency
+    pub geometry_shader: bool,
+}
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum BlendMode {
+    /// Overwrite the destination
+    Opaque,
+    /// Blend by source alpha
+    Alpha,
+    /// Add to the destination
+    Additive,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DepthBias {
+    pub constant: f32,
+    pub slope: f32,
+}
+
+impl BlendMode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "opaque" | "none" => Some(Self::Opaque),
+            "alpha" => Some(Self::Alpha),
+            "additive" | "add" => Some(Self::Additive),
+            _ => None,
+        }
+    }
+}
+
+pub fn parse_cull_mode(s: &str) -> Option<vk::CullModeFlags> {
+    match s.to_lowercase().as_str() {
+        "none" => Some(vk::CullModeFlags::NONE),
+        "front" => Some(vk::CullModeFlags::FRONT),
+        "back" => Some(vk::CullModeFlags::BACK),
+        "both" => Some(vk::CullModeFlags::FRONT_AND_BACK),
+        _ => None,
+    }
+}
+
+pub fn parse_front_face(s: &str) -> Option<vk::FrontFace> {
+    match s.to_lowercase().as_str() {
+        "cw" | "clockwise" => Some(vk::FrontFace::CLOCKWISE),
+        "ccw" | "counter_clockwise" => Some(vk::FrontFace::COUNTER_CLOCKWISE),
+        _ => None,
+    }
+}
+
+/// Modes other than fill require the fillModeNonSolid device feature
+pub fn parse_polygon_mode(s: &str) -> Option<vk::PolygonMode> {
+    match s.to_lowercase().as_str() {
+        "fill" => Some(vk::PolygonMode::FILL),
+        "line" => Some(vk::PolygonMode::LINE),
+        "point" => Some(vk::PolygonMode::POINT),
+        _ => None,
+    }
+}
+
+impl Default for PipelineOptions {
+    fn default() -> Self {
+        Self {
+            ordered: false,
+            blend: BlendMode::Opaque,
+            depth_test: true,
+            depth_write: true,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::CLOCKWISE,
+            polygon_mode: vk::PolygonMode::FILL,
+            line_width: 1.0,
+            depth_bias: None,
+            primitive_restart: false,
+        }
+    }
+}
+
+// Floats are compared by value and hashed by bits; NaNs are never produced by the option parser
+impl Eq for PipelineOptions {}
+
+impl Hash for PipelineOptions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ordered.hash(state);
+        self.blend.hash(state);
+        self.depth_test.hash(state);
+        self.depth_write.hash(state);
+        self.cull_mode.hash(state);
+        self.front_face.hash(state);
+        self.polygon_mode.hash(state);
+        self.line_width.to_bits().hash(state);
+        if let Some(bias) = self.depth_bias {
+            bias.constant.to_bits().hash(state);
+            bias.slope.to_bits().hash(state);
+        }
+        self.primitive_restart.hash(state);
+    }
+}
+
+/// Everything needed to build a graphics pipeline for the engine's render pass
+pub struct PipelineDesc<'a> {
+    pub vertex_spv: &'a [u8],
+    pub fragment_spv: &'a [u8],
+    pub topology: vk::PrimitiveTopology,
+    pub vertex_layout: &'a VertexLayout,
+    /// Per-instance attributes, which follow the vertex attributes' locations
+    pub instance_layout: &'a VertexLayout,
+    pub options: &'a PipelineOptions,
+}
+
+/// Binding of per-vertex data
+pub const VERTEX_BINDING: u32 = 0;
+/// Binding of per-instance data
+pub const INSTANCE_BINDING: u32 = 1;
+
+/// Build a graphics pipeline with dynamic viewport and scissor
+pub fn create_pipeline(
+    core: &Core,
+    desc: &PipelineDesc,
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline_cache: vk::PipelineCache,
+) -> Result<vk::Pipeline> {
+    let vertex = shader_module(core, desc.vertex_spv)?;
+    let fragment = match shader_module(core, desc.fragment_spv) {
+        Ok(f) => f,
+        Err(e) => {
+            unsafe { core.device.destroy_shader_module(Some(vertex), None) };
+            return Err(e);
+        }
+    };
+
+    let entry_point = CString::new("main")?;
+    let shader_stages = [
+        vk::PipelineShaderStageCreateInfoBuilder::new()
+            .stage(vk::ShaderStageFlagBits::VERTEX)
+            .module(vertex)
+            .name(&entry_point),
+        vk::PipelineShaderStageCreateInfoBuilder::new()
+            .stage(vk::ShaderStageFlagBits::FRAGMENT)
+            .module(fragment)
+            .name(&entry_point),
+    ];
+
+    let mut bindings = desc
+        .vertex_layout
+        .binding_descriptions(VERTEX_BINDING, vk::VertexInputRate::VERTEX);
+    bindings.extend(
+        desc.instance_layout
+            .binding_descriptions(INSTANCE_BINDING, vk::VertexInputRate::INSTANCE),
+    );
+
+    let first_instance_location = desc.vertex_layout.attributes.len() as u32;
+    let mut attributes = desc.vertex_layout.attribute_descriptions(VERTEX_BINDING, 0);
+    attributes.extend(
+        desc.instance_layout
+            .attribute_descriptions(INSTANCE_BINDING, first_instance_location),
+    );
+    let vertex_input = vk::PipelineVertexInputStateCreateInfoBuilder::new()
+        .vertex_binding_descriptions(&bindings)
+        .vertex_attribute_descriptions(&attributes);
+
+    let input_assembly = vk::PipelineInputAssemblyStateCreateInfoBuilder::new()
+        .topology(desc.topology)
+        .primitive_restart_enable(desc.options.primitive_restart);
+
+    let viewport_state = vk::PipelineViewportStateCreateInfoBuilder::new()
+        .viewport_count(1)
+        .scissor_count(1);
+
+    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let dynamic_state =
+        vk::PipelineDynamicStateCreateInfoBuilder::new().dynamic_states(&dynamic_states);
+
+    let options = desc.options;
+    let depth_bias = options.depth_bias.unwrap_or(DepthBias {
+        constant: 0.0,
+        slope: 0.0,
+    });
+    let rasterizer = vk::PipelineRasterizationStateCreateInfoBuilder::new()
+        .depth_clamp_enable(false)
+        .rasterizer_discard_enable(false)
+        .polygon_mode(options.polygon_mode)
+        .line_width(options.line_width)
+        .cull_mode(options.cull_mode)
+        .front_face(options.front_face)
+        .depth_bias_enable(options.depth_bias.is_some())
+        .depth_bias_constant_factor(depth_bias.constant)
+        .depth_bias_slope_factor(depth_bias.slope);
+
+    let multisampling = vk::PipelineMultisampleStateCreateInfoBuilder::new()
+        .sample_shading_enable(false)
+        .rasterization_samples(vk::SampleCountFlagBits::_1);
+
+    let color_blend_attachment = vk::PipelineColorBlendAttachmentStateBuilder::new()
+        .color_write_mask(
+            vk::ColorComponentFlags::R
+                | vk::ColorComponentFlags::G
+                | vk::ColorComponentFlags::B
+                | vk::ColorComponentFlags::A,
+        );
+    let color_blend_attachment = match options.blend {
+        BlendMode::Opaque => color_blend_attachment.blend_enable(false),
+        BlendMode::Alpha => color_blend_attachment
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+            .alpha_blend_op(vk::BlendOp::ADD),
+        BlendMode::Additive => color_blend_attachment
+            .blend_enable(true)
+            .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+            .dst_color_blend_factor(vk::BlendFactor::ONE)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+            .alpha_blend_op(vk::BlendOp::ADD),
+    };
+    let color_blend_attachments = [color_blend_attachment];
+    let color_blending = vk::PipelineColorBlendStateCreateInfoBuilder::new()
+        .logic_op_enable(false)
+        .attachments(&color_blend_attachments);
+
+    let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfoBuilder::new()
+        .depth_test_enable(options.depth_test)
+        .depth_write_enable(options.depth_write)
+        .depth_compare_op(vk::CompareOp::LESS)
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false);
+
+    let create_info = vk::GraphicsPipelineCreateInfoBuilder::new()
+        .stages(&shader_stages)
+        .vertex_input_state(&vertex_input)
+        .input_assembly_state(&input_assembly)
+        .viewport_state(&viewport_state)
+        .rasterization_state(&rasterizer)
+        .multisample_state(&multisampling)
+        .depth_stencil_state(&depth_stencil_state)
+        .color_blend_state(&color_blending)
+        .dynamic_state(&dynamic_state)
+        .layout(pipeline_layout)
+        .render_pass(render_pass)
+        .subpass(0);
+
+    let pipeline = unsafe { core.device.create_graphics_pipelines(Some(pipeline_cache), &[create_info], None) }
+        .result()
+        .map(|pipelines| pipelines[0]);
+
+    unsafe {
+        core.device.destroy_shader_module(Some(fragment), None);
+        core.device.destroy_shader_module(Some(vertex), None);
+    }
+
+    Ok(pipeline?)
+}
+
+fn shader_module(core: &Core, spv: &[u8]) -> Result<vk::ShaderModule> {
+    ensure!(spv.len() % 4 == 0, "SPIR-V length is not a multiple of four bytes");
+    let code: Vec<u32> = spv
+        .chunks_exact(4)
+        .map(|word| u32::from_ne_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+    let create_info = vk::ShaderModuleCreateInfoBuilder::new().code(&code);
+    Ok(unsafe { core.device.create_shader_module(&create_info, None, None) }.result()?)
+}
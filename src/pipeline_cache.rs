@@ -0,0 +1,89 @@
+use crate::spirv_cache::CACHE_DIR;
+use anyhow::Result;
+use std::path::PathBuf;
+use watertender::prelude::*;
+
+/// Saved next to the SPIR-V cache, in the same directory
+const PIPELINE_CACHE_FILE: &str = "pipelines.bin";
+
+/// Size of the header at the start of pipeline cache data, in its first version
+const HEADER_SIZE: usize = 32;
+const HEADER_VERSION_ONE: u32 = 1;
+
+fn cache_path() -> PathBuf {
+    PathBuf::from(CACHE_DIR).join(PIPELINE_CACHE_FILE)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    // The header is always little-endian, regardless of the host
+    u32::from_le_bytes(bytes)
+}
+
+/// Whether cache data was written by this driver for this device. Drivers are meant to reject
+/// anything else themselves, but not all of them do so safely.
+fn header_matches(data: &[u8], properties: &vk::PhysicalDeviceProperties) -> bool {
+    data.len() >= HEADER_SIZE
+        && read_u32(data, 0) as usize >= HEADER_SIZE
+        && read_u32(data, 4) == HEADER_VERSION_ONE
+        && read_u32(data, 8) == properties.vendor_id
+        && read_u32(data, 12) == properties.device_id
+        && data[16..32] == properties.pipeline_cache_uuid
+}
+
+/// Create a pipeline cache, starting from the one saved by the last run if it's for the same
+/// device and driver
+pub fn load(core: &Core) -> Result<vk::PipelineCache> {
+    let properties =
+        unsafe { core.instance.get_physical_device_properties(core.physical_device, None) };
+
+    let data = std::fs::read(cache_path())
+        .ok()
+        .filter(|data| header_matches(data, &properties))
+        .unwrap_or_default();
+
+    let mut create_info = vk::PipelineCacheCreateInfoBuilder::new();
+    if !data.is_empty() {
+        create_info = create_info
+            .initial_data_size(data.len())
+            .initial_data(data.as_ptr() as _);
+    }
+
+    let cache = unsafe { core.device.create_pipeline_cache(&create_info, None, None) };
+    match cache.result() {
+        Ok(cache) => Ok(cache),
+        // Shouldn't happen given the header check, but an empty cache is always fine
+        Err(_) if !data.is_empty() => {
+            let create_info = vk::PipelineCacheCreateInfoBuilder::new();
+            Ok(unsafe { core.device.create_pipeline_cache(&create_info, None, None) }.result()?)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Write a pipeline cache to disk, for the next run
+pub fn save(core: &Core, cache: vk::PipelineCache) -> Result<()> {
+    let mut size = 0;
+    unsafe {
+        core.device
+            .get_pipeline_cache_data(cache, &mut size, std::ptr::null_mut())
+            .result()?;
+    }
+
+    let mut data = vec![0u8; size];
+    unsafe {
+        core.device
+            .get_pipeline_cache_data(cache, &mut size, data.as_mut_ptr() as _)
+            .result()?;
+    }
+    data.truncate(size);
+
+    // Written to a temporary file first, so that an interrupted save doesn't leave a corrupt cache
+    std::fs::create_dir_all(CACHE_DIR)?;
+    let path = cache_path();
+    let temp = path.with_extension("tmp");
+    std::fs::write(&temp, &data)?;
+    std::fs::rename(temp, path)?;
+    Ok(())
+}
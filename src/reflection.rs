@@ -0,0 +1,232 @@
+use crate::vertex_layout::{AttributeFormat, VertexLayout};
+use anyhow::{format_err, Result};
+use spirv_reflect::types::{ReflectDecorationFlags, ReflectDescriptorType, ReflectFormat};
+use spirv_reflect::ShaderModule;
+use watertender::vk::{DescriptorType, ShaderStageFlags};
+
+/// An input variable of a vertex shader
+#[derive(Clone, Debug)]
+pub struct ShaderInput {
+    pub location: u32,
+    pub name: String,
+    pub components: u32,
+    pub format: AttributeFormat,
+}
+
+/// Resources the engine binds for every pipeline, which shaders may use
+pub struct PipelineInterface {
+    /// Bindings of descriptor set 0; no other sets are bound
+    pub bindings: Vec<InterfaceBinding>,
+    /// Bytes of push constants written before each draw
+    pub push_constant_size: u32,
+    pub push_constant_stages: ShaderStageFlags,
+}
+
+pub struct InterfaceBinding {
+    pub binding: u32,
+    pub descriptor_type: DescriptorType,
+    /// Name used in error messages
+    pub name: &'static str,
+    /// Size of the data behind a buffer binding, if fixed
+    pub size: Option<u32>,
+}
+
+fn load(spv: &[u8]) -> Result<ShaderModule> {
+    ShaderModule::load_u8_data(spv).map_err(|e| format_err!("Failed to reflect SPIR-V; {}", e))
+}
+
+/// Find the vertex inputs of a vertex shader, not including built-ins
+pub fn vertex_inputs(spv: &[u8]) -> Result<Vec<ShaderInput>> {
+    let module = load(spv)?;
+    let variables = module
+        .enumerate_input_variables(None)
+        .map_err(|e| format_err!("Failed to reflect inputs; {}", e))?;
+
+    let mut inputs = vec![];
+    for var in variables {
+        if var.decoration_flags.contains(ReflectDecorationFlags::BUILT_IN) {
+            continue;
+        }
+
+        let (components, format) = match var.format {
+            ReflectFormat::R32_SFLOAT => (1, AttributeFormat::F32),
+            ReflectFormat::R32G32_SFLOAT => (2, AttributeFormat::F32),
+            ReflectFormat::R32G32B32_SFLOAT => (3, AttributeFormat::F32),
+            ReflectFormat::R32G32B32A32_SFLOAT => (4, AttributeFormat::F32),
+            ReflectFormat::R32_SINT => (1, AttributeFormat::I32),
+            ReflectFormat::R32G32_SINT => (2, AttributeFormat::I32),
+            ReflectFormat::R32G32B32_SINT => (3, AttributeFormat::I32),
+            ReflectFormat::R32G32B32A32_SINT => (4, AttributeFormat::I32),
+            ReflectFormat::R32_UINT => (1, AttributeFormat::U32),
+            ReflectFormat::R32G32_UINT => (2, AttributeFormat::U32),
+            ReflectFormat::R32G32B32_UINT => (3, AttributeFormat::U32),
+            ReflectFormat::R32G32B32A32_UINT => (4, AttributeFormat::U32),
+            ReflectFormat::Undefined => {
+                return Err(format_err!(
+                    "Vertex input \"{}\" at location {} has an unsupported type",
+                    var.name,
+                    var.location
+                ))
+            }
+        };
+
+        inputs.push(ShaderInput {
+            location: var.location,
+            name: var.name,
+            components,
+            format,
+        });
+    }
+
+    inputs.sort_by_key(|i| i.location);
+    Ok(inputs)
+}
+
+/// Check that vertex and instance attributes provide exactly what the shader's inputs expect
+pub fn check_vertex_layout(
+    inputs: &[ShaderInput],
+    vertex: &VertexLayout,
+    instance: &VertexLayout,
+) -> Result<(), String> {
+    let layout = vertex.concat(instance);
+    for input in inputs {
+        let attr = layout.attributes.get(input.location as usize).ok_or_else(|| {
+            format!(
+                "shader input \"{}\" at location {} is not provided; the mesh and instance data have {} attribute(s)",
+                input.name,
+                input.location,
+                layout.attributes.len()
+            )
+        })?;
+
+        if attr.format != input.format || attr.components != input.components {
+            return Err(format!(
+                "shader input \"{}\" at location {} expects {} x {}, but attribute \"{}\" is {} x {}",
+                input.name,
+                input.location,
+                input.components,
+                input.format.name(),
+                attr.name,
+                attr.components,
+                attr.format.name(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Blocks declared without an instance name have an empty name
+fn block_label(name: &str) -> String {
+    match name {
+        "" => "block".into(),
+        name => format!("\"{}\"", name),
+    }
+}
+
+fn descriptor_type_name(ty: DescriptorType) -> &'static str {
+    match ty {
+        DescriptorType::UNIFORM_BUFFER => "uniform buffer",
+        DescriptorType::STORAGE_BUFFER => "storage buffer",
+        _ => "descriptor",
+    }
+}
+
+/// Check that a shader stage only uses the descriptors and push constants the engine provides,
+/// returning a description of each problem found
+pub fn check_interface(
+    spv: &[u8],
+    stage: ShaderStageFlags,
+    interface: &PipelineInterface,
+) -> Vec<String> {
+    let module = match load(spv) {
+        Ok(m) => m,
+        Err(e) => return vec![e.to_string()],
+    };
+
+    let mut problems = vec![];
+    if stage == ShaderStageFlags::VERTEX {
+        if let Err(e) = vertex_inputs(spv) {
+            problems.push(e.to_string());
+        }
+    }
+
+    match module.enumerate_descriptor_bindings(None) {
+        Ok(bindings) => {
+            for binding in bindings {
+                if binding.set != 0 {
+                    problems.push(format!(
+                        "{} uses descriptor set {}, but only set 0 is bound",
+                        block_label(&binding.name), binding.set
+                    ));
+                    continue;
+                }
+
+                let provided = match interface.bindings.iter().find(|b| b.binding == binding.binding) {
+                    Some(b) => b,
+                    None => {
+                        let available = interface
+                            .bindings
+                            .iter()
+                            .map(|b| format!("{} ({})", b.binding, b.name))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        problems.push(format!(
+                            "{} uses binding {}, which the engine doesn't provide; available bindings are {}",
+                            block_label(&binding.name), binding.binding, available
+                        ));
+                        continue;
+                    }
+                };
+
+                let descriptor_type = match binding.descriptor_type {
+                    ReflectDescriptorType::UniformBuffer => Some(DescriptorType::UNIFORM_BUFFER),
+                    ReflectDescriptorType::StorageBuffer => Some(DescriptorType::STORAGE_BUFFER),
+                    _ => None,
+                };
+                if descriptor_type != Some(provided.descriptor_type) {
+                    problems.push(format!(
+                        "{} at binding {} is a {:?}, but the engine binds the {} {} there",
+                        block_label(&binding.name),
+                        binding.binding,
+                        binding.descriptor_type,
+                        provided.name,
+                        descriptor_type_name(provided.descriptor_type),
+                    ));
+                    continue;
+                }
+
+                if let Some(size) = provided.size {
+                    if binding.block.size > size {
+                        problems.push(format!(
+                            "{} at binding {} is {} bytes, but the engine's {} is only {} bytes",
+                            block_label(&binding.name), binding.binding, binding.block.size, provided.name, size
+                        ));
+                    }
+                }
+            }
+        }
+        Err(e) => problems.push(format!("Failed to reflect descriptor bindings; {}", e)),
+    }
+
+    match module.enumerate_push_constant_blocks(None) {
+        Ok(blocks) => {
+            for block in blocks {
+                if !interface.push_constant_stages.contains(stage) {
+                    problems.push(format!(
+                        "push constant {} is used, but push constants are only available to the {:?} stage",
+                        block_label(&block.name), interface.push_constant_stages
+                    ));
+                } else if block.size > interface.push_constant_size {
+                    problems.push(format!(
+                        "push constant {} is {} bytes, but the engine only pushes {} bytes",
+                        block_label(&block.name), block.size, interface.push_constant_size
+                    ));
+                }
+            }
+        }
+        Err(e) => problems.push(format!("Failed to reflect push constants; {}", e)),
+    }
+
+    problems
+}
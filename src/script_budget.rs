@@ -0,0 +1,125 @@
+use crate::console::console_print;
+use mlua::prelude::*;
+use mlua::HookTriggers;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Number of VM instructions between checks of the clock
+const CHECK_INTERVAL: u32 = 10_000;
+
+/// How long a single call into script code may run
+#[derive(Debug, Copy, Clone)]
+pub struct Budget {
+    /// Past this, a warning is printed
+    pub soft: Duration,
+    /// Past this, the call is aborted with an error
+    pub hard: Duration,
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Self {
+            soft: Duration::from_millis(50),
+            hard: Duration::from_secs(2),
+        }
+    }
+}
+
+/// Enforces a `Budget` on calls into Lua, using an instruction count hook.
+/// LuaJIT does not run hooks from within compiled traces, so the JIT compiler is turned off;
+/// otherwise a tight loop such as `while true do end` would never be interrupted.
+pub struct ScriptBudget {
+    state: Rc<BudgetState>,
+}
+
+struct BudgetState {
+    budget: Cell<Budget>,
+    call_start: Cell<Option<Instant>>,
+    warned: Cell<bool>,
+}
+
+impl ScriptBudget {
+    /// Install the hook on the given Lua state, and turn off its JIT compiler
+    pub fn install(lua: &Lua, budget: Budget) -> LuaResult<Self> {
+        // Traces compiled by the JIT never call the hook, so run everything in the interpreter
+        if let Some(jit) = lua.globals().get::<_, Option<LuaTable>>("jit")? {
+            jit.get::<_, LuaFunction>("off")?.call::<_, ()>(())?;
+            jit.get::<_, LuaFunction>("flush")?.call::<_, ()>(())?;
+        }
+
+        let state = Rc::new(BudgetState {
+            budget: Cell::new(budget),
+            call_start: Cell::new(None),
+            warned: Cell::new(false),
+        });
+
+        let hook_state = state.clone();
+        let triggers = HookTriggers {
+            every_nth_instruction: Some(CHECK_INTERVAL),
+            ..Default::default()
+        };
+        lua.set_hook(triggers, move |_, _| {
+            let start = match hook_state.call_start.get() {
+                Some(s) => s,
+                None => return Ok(()),
+            };
+
+            let budget = hook_state.budget.get();
+            let elapsed = start.elapsed();
+            if elapsed > budget.hard {
+                return Err(LuaError::RuntimeError(format!(
+                    "Script exceeded the hard time limit of {:?}",
+                    budget.hard
+                )));
+            }
+
+            if elapsed > budget.soft && !hook_state.warned.replace(true) {
+                console_print(&format!(
+                    "Warning: script has been running for over {:?}",
+                    budget.soft
+                ));
+            }
+
+            Ok(())
+        })?;
+
+        Ok(Self { state })
+    }
+
+    pub fn budget(&self) -> Budget {
+        self.state.budget.get()
+    }
+
+    pub fn set_budget(&self, budget: Budget) {
+        self.state.budget.set(budget);
+    }
+
+    /// Run `f`, which calls into script code named `what`, under the budget
+    pub fn call<R>(&self, what: &str, f: impl FnOnce() -> LuaResult<R>) -> LuaResult<R> {
+        // Calls made from within an already budgeted call count towards the outer one
+        if self.state.call_start.get().is_some() {
+            return f();
+        }
+
+        let start = Instant::now();
+        self.state.call_start.set(Some(start));
+        self.state.warned.set(false);
+
+        let ret = f();
+
+        self.state.call_start.set(None);
+
+        // Short calls may finish before the hook ever sees them go over
+        let budget = self.budget();
+        let elapsed = start.elapsed();
+        if elapsed > budget.soft && !self.state.warned.get() {
+            console_print(&format!(
+                "Warning: {} took {:?}, over the soft limit of {:?}",
+                what, elapsed, budget.soft
+            ));
+        }
+
+        ret
+    }
+}
@@ -0,0 +1,98 @@
+use mlua::prelude::*;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Files and modules pulled in by the running script
+#[derive(Debug)]
+pub struct ScriptDeps {
+    /// Kept separately, as it may briefly go missing while an editor saves it
+    main_script: PathBuf,
+    files: BTreeSet<PathBuf>,
+    modules: HashSet<String>,
+}
+
+impl ScriptDeps {
+    pub fn new(main_script: &Path) -> Self {
+        Self {
+            main_script: main_script
+                .canonicalize()
+                .unwrap_or_else(|_| main_script.to_path_buf()),
+            files: BTreeSet::new(),
+            modules: HashSet::new(),
+        }
+    }
+
+    /// Forget everything but the main script, returning the names of the modules which were
+    /// loaded through require() so that they may be unloaded
+    pub fn reset(&mut self) -> Vec<String> {
+        self.files.clear();
+        self.modules.drain().collect()
+    }
+
+    /// Whether the given file is used by the script
+    pub fn contains(&self, path: &Path) -> bool {
+        match path.canonicalize() {
+            Ok(path) => path == self.main_script || self.files.contains(&path),
+            Err(_) => false,
+        }
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        let others = self
+            .files
+            .iter()
+            .map(|p| p.as_path())
+            .filter(move |p| **p != *self.main_script);
+        std::iter::once(self.main_script.as_path()).chain(others)
+    }
+
+    fn record_file(&mut self, path: impl AsRef<Path>) {
+        // Files which don't exist yet can't be watched anyway
+        if let Ok(path) = path.as_ref().canonicalize() {
+            self.files.insert(path);
+        }
+    }
+}
+
+/// Wrap `dofile`, `loadfile` and `require` so that the files they load are recorded
+pub fn install(lua: &'static Lua, deps: Rc<RefCell<ScriptDeps>>) -> LuaResult<()> {
+    let globals = lua.globals();
+
+    // The path is the first argument to both of these
+    for &name in &["dofile", "loadfile"] {
+        let original: LuaFunction = globals.get(name)?;
+        let deps = deps.clone();
+        let wrapped = lua.create_function(move |_, args: LuaMultiValue| {
+            if let Some(LuaValue::String(path)) = args.iter().next() {
+                deps.borrow_mut().record_file(path.to_str()?);
+            }
+            original.call::<_, LuaMultiValue>(args)
+        })?;
+        globals.set(name, wrapped)?;
+    }
+
+    // Ask the package library where require() will find the module. Only modules loaded from a
+    // file are recorded; unloading built in ones such as `bit` would make them unavailable.
+    let original: LuaFunction = globals.get("require")?;
+    let package: LuaTable = globals.get("package")?;
+    let searchpath: Option<LuaFunction> = package.get("searchpath")?;
+    let wrapped = lua.create_function(move |_, name: String| {
+        if let Some(searchpath) = &searchpath {
+            let search: String = package.get("path")?;
+            let (found, _): (Option<String>, Option<String>) =
+                searchpath.call((name.clone(), search))?;
+            if let Some(file) = found {
+                let mut deps = deps.borrow_mut();
+                deps.modules.insert(name.clone());
+                deps.record_file(file);
+            }
+        }
+
+        original.call::<_, LuaMultiValue>(name)
+    })?;
+    globals.set("require", wrapped)?;
+
+    Ok(())
+}
@@ -1,21 +1,71 @@
-use crate::engine::{Shader, RenderEngine};
+use crate::console::console_print;
+use crate::engine::{FallbackSpv, Shader, RenderEngine};
+use crate::pipeline::PipelineOptions;
+use crate::reflection;
+use crate::spirv_cache::{CacheKey, IncludeRecord, SpirvCache};
 use slotmap::SecondaryMap;
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use watertender::vk::PrimitiveTopology;
-use anyhow::Result;
-use shaderc::{ShaderKind, CompilationArtifact, Compiler};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use watertender::vk::{PrimitiveTopology, ShaderStageFlags};
+use anyhow::{format_err, Result};
+use shaderc::{
+    CompilationArtifact, CompileOptions, Compiler, IncludeType, ResolvedInclude, ShaderKind,
+};
+
+/// Environment variable naming the directory searched for `#include`s, after the including
+/// file's own directory
+const SHADER_ROOT_VAR: &str = "MICRO_ENGINE_SHADER_ROOT";
+const DEFAULT_SHADER_ROOT: &str = "shaders";
+
+/// Files included by a shader's sources
+pub type Includes = BTreeSet<PathBuf>;
+
+/// Where the GLSL for a shader stage comes from
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum ShaderSource {
+    /// Read from a file, and recompiled whenever it changes
+    File(String),
+    /// Given by the script. The name is used in error messages and to resolve relative includes.
+    Inline { name: String, source: String },
+}
+
+impl ShaderSource {
+    fn name(&self) -> &str {
+        match self {
+            ShaderSource::File(path) => path,
+            ShaderSource::Inline { name, .. } => name,
+        }
+    }
+
+    fn load(&self) -> std::io::Result<String> {
+        match self {
+            ShaderSource::File(path) => std::fs::read_to_string(path),
+            ShaderSource::Inline { source, .. } => Ok(source.clone()),
+        }
+    }
+}
+
+/// Preprocessor macros passed to both stages of a shader, with optional values
+pub type Defines = BTreeMap<String, Option<String>>;
 
 #[derive(Clone, Debug, Hash, PartialEq)]
 pub struct UniquePipeline {
-    pub vertex_path: String,
-    pub fragment_path: String,
+    pub vertex: ShaderSource,
+    pub fragment: ShaderSource,
     pub primitive: PrimitiveTopology,
+    pub options: PipelineOptions,
+    /// The same files with different defines are compiled separately
+    pub defines: Defines,
 }
 
 pub struct ShaderUpdateCalculator {
     shader_to_unique: SecondaryMap<Shader, UniquePipeline>,
     path_to_shader: HashMap<PathBuf, Vec<Shader>>,
+    /// Files included by each shader as of its last compilation
+    shader_includes: SecondaryMap<Shader, Includes>,
+    include_to_shader: HashMap<PathBuf, HashSet<Shader>>,
     updates: HashSet<Shader>,
 }
 
@@ -24,26 +74,61 @@ impl ShaderUpdateCalculator {
         Self {
             shader_to_unique: Default::default(),
             path_to_shader: Default::default(),
+            shader_includes: Default::default(),
+            include_to_shader: Default::default(),
             updates: Default::default(),
         }
     }
 
-    pub fn track_shader(&mut self, handle: Shader, unique: UniquePipeline) -> Result<()> {
+    /// Start tracking a shader, and queue it to be compiled. A source file which doesn't exist is
+    /// reported when compiling, and still watched so that creating it fixes the shader.
+    pub fn track_shader(&mut self, handle: Shader, unique: UniquePipeline) {
         self.shader_to_unique.insert(handle, unique.clone());
 
-        self.path_to_shader
-            .entry(std::fs::canonicalize(unique.vertex_path)?)
-            .or_default()
-            .push(handle);
-
-        self.path_to_shader
-            .entry(std::fs::canonicalize(unique.fragment_path)?)
-            .or_default()
-            .push(handle);
+        // Inline sources only change when the script tracks them again
+        for source in [unique.vertex, unique.fragment].iter() {
+            if let ShaderSource::File(path) = source {
+                if let Some(path) = canonical_candidate(Path::new(path)) {
+                    self.path_to_shader.entry(path).or_default().push(handle);
+                }
+            }
+        }
 
         self.updates.insert(handle);
+    }
+
+    /// Stop tracking a shader, for instance because it was freed
+    pub fn untrack_shader(&mut self, handle: Shader) {
+        self.shader_to_unique.remove(handle);
+        for shaders in self.path_to_shader.values_mut() {
+            shaders.retain(|&s| s != handle);
+        }
+        self.path_to_shader.retain(|_, shaders| !shaders.is_empty());
+        self.set_includes(handle, Includes::new());
+        self.shader_includes.remove(handle);
+        self.updates.remove(&handle);
+    }
 
-        Ok(())
+    /// Record the files a shader included when it was last compiled, so that changes to them
+    /// recompile it
+    pub fn set_includes(&mut self, handle: Shader, includes: Includes) {
+        if let Some(old) = self.shader_includes.get(handle) {
+            for path in old {
+                if let Some(shaders) = self.include_to_shader.get_mut(path) {
+                    shaders.remove(&handle);
+                }
+            }
+        }
+        self.include_to_shader.retain(|_, shaders| !shaders.is_empty());
+
+        if !self.shader_to_unique.contains_key(handle) {
+            return;
+        }
+
+        for path in &includes {
+            self.include_to_shader.entry(path.clone()).or_default().insert(handle);
+        }
+        self.shader_includes.insert(handle, includes);
     }
 
     pub fn shader_file_touched(&mut self, path: &std::path::Path) {
@@ -52,6 +137,9 @@ impl ShaderUpdateCalculator {
             if let Some(shaders) = maybe_shaders {
                 self.updates.extend(shaders);
             }
+            if let Some(shaders) = self.include_to_shader.get(&path) {
+                self.updates.extend(shaders);
+            }
         }
     }
 
@@ -68,41 +156,615 @@ impl ShaderUpdateCalculator {
     }
 }
 
-pub fn compile_jobs(compiler: &mut Compiler, jobs: &[(Shader, UniquePipeline)], engine: &mut RenderEngine) -> Result<()> {
+/// A problem found while compiling a shader
+#[derive(Clone, Debug)]
+pub struct Diagnostic {
+    /// Source file, include or inline shader name
+    pub file: String,
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.file, line, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+/// Outcome of the latest compilation of a shader
+#[derive(Clone, Debug)]
+pub enum ShaderStatus {
+    /// Waiting to be compiled
+    Pending,
+    Compiled,
+    /// If an earlier version compiled it is still drawn, otherwise the fallback shader is
+    Failed(Vec<Diagnostic>),
+}
+
+/// Result of compiling a tracked shader
+pub struct CompileResult {
+    pub shader: Shader,
+    /// Files included by either stage
+    pub includes: Includes,
+    pub status: ShaderStatus,
+}
+
+/// A shader to be compiled. The generation increases each time the same shader is submitted,
+/// so that results of older edits can be told apart.
+struct CompileJob {
+    shader: Shader,
+    generation: u64,
+    pipeline: UniquePipeline,
+}
+
+/// Both stages of a shader, compiled on the worker thread
+struct CompiledPipeline {
+    shader: Shader,
+    generation: u64,
+    pipeline: UniquePipeline,
+    vertex: Compiled,
+    fragment: Compiled,
+}
+
+/// Compiles shaders on a background thread, so that the window keeps drawing with the previous
+/// version of a shader until the new one is ready
+pub struct CompileWorker {
+    jobs: Sender<Vec<CompileJob>>,
+    results: Receiver<CompiledPipeline>,
+    /// Latest generation submitted for each shader; other results are stale
+    generations: SecondaryMap<Shader, u64>,
+    next_generation: u64,
+}
+
+impl CompileWorker {
+    /// Start the worker, and give the engine the fallback shader once the worker has compiled it
+    pub fn new(cache: SpirvCache, engine: &mut RenderEngine) -> Result<Self> {
+        let (jobs_tx, jobs_rx) = mpsc::channel();
+        let (results_tx, results_rx) = mpsc::channel();
+        let (fallback_tx, fallback_rx) = mpsc::channel();
+
+        // The compiler is created on the worker, as it can't be sent between threads
+        std::thread::spawn(move || {
+            let mut compiler = match Compiler::new() {
+                Some(c) => c,
+                None => {
+                    let _ = fallback_tx.send(Err(format_err!("Failed to init shaderc")));
+                    return;
+                }
+            };
+            let _ = fallback_tx.send(compile_fallback(&mut compiler, &cache));
+            compile_worker(&mut compiler, &cache, jobs_rx, results_tx);
+        });
+
+        let fallback = fallback_rx
+            .recv()
+            .map_err(|_| format_err!("Shader compiler thread stopped"))??;
+        engine.set_fallback_shader(fallback);
+
+        Ok(Self {
+            jobs: jobs_tx,
+            results: results_rx,
+            generations: SecondaryMap::new(),
+            next_generation: 0,
+        })
+    }
+
+    /// Queue shaders to be compiled, superseding any earlier versions still in progress
+    pub fn submit(&mut self, jobs: Vec<(Shader, UniquePipeline)>) -> Result<()> {
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        let jobs = jobs
+            .into_iter()
+            .map(|(shader, pipeline)| {
+                self.next_generation += 1;
+                self.generations.insert(shader, self.next_generation);
+                CompileJob {
+                    shader,
+                    generation: self.next_generation,
+                    pipeline,
+                }
+            })
+            .collect();
+
+        self.jobs
+            .send(jobs)
+            .map_err(|_| format_err!("Shader compiler thread stopped"))
+    }
+
+    /// Drop any results for a shader which is no longer tracked
+    pub fn forget(&mut self, shader: Shader) {
+        self.generations.remove(shader);
+    }
+
+    /// Add shaders which finished compiling to the engine, returning the files each one included
+    pub fn finished(&mut self, engine: &mut RenderEngine) -> Result<Vec<CompileResult>> {
+        let mut results = vec![];
+        for compiled in self.results.try_iter() {
+            if self.generations.get(compiled.shader) != Some(&compiled.generation) {
+                continue;
+            }
+            self.generations.remove(compiled.shader);
+            results.push(add_to_engine(compiled, engine)?);
+        }
+        Ok(results)
+    }
+}
+
+/// Compile jobs as they arrive, until the main thread hangs up
+fn compile_worker(
+    compiler: &mut Compiler,
+    cache: &SpirvCache,
+    jobs: Receiver<Vec<CompileJob>>,
+    results: Sender<CompiledPipeline>,
+) {
+    while let Ok(first) = jobs.recv() {
+        // Take everything queued up so far, so that a shader edited several times in a row is
+        // only compiled once
+        let mut latest: Vec<CompileJob> = vec![];
+        for job in first.into_iter().chain(jobs.try_iter().flatten()) {
+            latest.retain(|j| j.shader != job.shader);
+            latest.push(job);
+        }
+
+        for compiled in compile_batch(compiler, cache, latest) {
+            if results.send(compiled).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn compile_batch(
+    compiler: &mut Compiler,
+    cache: &SpirvCache,
+    jobs: Vec<CompileJob>,
+) -> Vec<CompiledPipeline> {
     // Cache to speed up compilation of shaders. Probably unneeded but I wanted to okay
-    let mut fragment_artefacts: HashMap<String, Option<CompilationArtifact>> = HashMap::new();
-    let mut vertex_artefacts: HashMap<String, Option<CompilationArtifact>> = HashMap::new();
+    let mut fragment_artefacts: HashMap<(ShaderSource, Defines), Compiled> = HashMap::new();
+    let mut vertex_artefacts: HashMap<(ShaderSource, Defines), Compiled> = HashMap::new();
+    let root = shader_root();
 
-    for (shader, paths) in jobs {
+    let mut compiled = vec![];
+    for job in jobs {
+        let paths = &job.pipeline;
         let vertex = vertex_artefacts
-            .entry(paths.vertex_path.clone())
-            .or_insert_with(|| compile_nice(compiler, &paths.vertex_path, ShaderKind::Vertex));
+            .entry((paths.vertex.clone(), paths.defines.clone()))
+            .or_insert_with(|| {
+                compile_nice(compiler, cache, &paths.vertex, ShaderKind::Vertex, &paths.defines, &root)
+            })
+            .clone();
         let fragment = fragment_artefacts
-            .entry(paths.fragment_path.clone())
-            .or_insert_with(|| compile_nice(compiler, &paths.fragment_path, ShaderKind::Fragment));
-        if let Some((vertex, fragment)) = vertex.as_ref().zip(fragment.as_ref()) {
-            engine.add_shader(&vertex.as_binary_u8(), fragment.as_binary_u8(), paths.primitive, *shader)?;
+            .entry((paths.fragment.clone(), paths.defines.clone()))
+            .or_insert_with(|| {
+                compile_nice(compiler, cache, &paths.fragment, ShaderKind::Fragment, &paths.defines, &root)
+            })
+            .clone();
+
+        compiled.push(CompiledPipeline {
+            shader: job.shader,
+            generation: job.generation,
+            pipeline: job.pipeline,
+            vertex,
+            fragment,
+        });
+    }
+    compiled
+}
+
+/// Check a compiled shader against the engine's pipeline layout and add it, or the fallback if
+/// it failed
+fn add_to_engine(compiled: CompiledPipeline, engine: &mut RenderEngine) -> Result<CompileResult> {
+    let CompiledPipeline {
+        shader,
+        pipeline: paths,
+        vertex,
+        fragment,
+        ..
+    } = compiled;
+
+    let includes = vertex.includes.union(&fragment.includes).cloned().collect();
+
+    let diagnostics: Vec<Diagnostic> = match (&vertex.artefact, &fragment.artefact) {
+        (Ok(vertex_art), Ok(fragment_art)) => {
+            // Shaders must agree with the engine's pipeline layout
+            let diagnostics = check_interface(
+                &paths.vertex,
+                vertex_art,
+                ShaderStageFlags::VERTEX,
+                engine,
+            )
+            .into_iter()
+            .chain(check_interface(
+                &paths.fragment,
+                fragment_art,
+                ShaderStageFlags::FRAGMENT,
+                engine,
+            ))
+            .collect();
+
+            if !diagnostics.is_empty() {
+                let heading = format!(
+                    "Shader \"{}\", \"{}\" doesn't match the engine's pipeline layout",
+                    paths.vertex.name(),
+                    paths.fragment.name()
+                );
+                print_diagnostics(&heading, &diagnostics);
+            }
+            diagnostics
+        }
+        (vertex, fragment) => vertex
+            .as_ref()
+            .err()
+            .into_iter()
+            .chain(fragment.as_ref().err())
+            .flatten()
+            .cloned()
+            .collect(),
+    };
+
+    let status = match (&vertex.artefact, &fragment.artefact) {
+        (Ok(vertex), Ok(fragment)) if diagnostics.is_empty() => {
+            engine.add_shader(vertex, fragment, paths.primitive, &paths.options, shader)?;
+            ShaderStatus::Compiled
+        }
+        _ => {
+            engine.add_fallback_shader(paths.primitive, &paths.options, shader)?;
+            ShaderStatus::Failed(diagnostics)
         }
+    };
+
+    Ok(CompileResult {
+        shader,
+        includes,
+        status,
+    })
+}
+
+/// Compile the shader drawn in place of shaders which have never compiled
+fn compile_fallback(compiler: &mut Compiler, cache: &SpirvCache) -> Result<FallbackSpv> {
+    let root = shader_root();
+    let vertex = ShaderSource::Inline {
+        name: "fallback.vert".into(),
+        source: include_str!("../shaders/fallback.vert").into(),
+    };
+    let procedural_vertex = ShaderSource::Inline {
+        name: "fallback_procedural.vert".into(),
+        source: include_str!("../shaders/fallback_procedural.vert").into(),
+    };
+    let fragment = ShaderSource::Inline {
+        name: "fallback.frag".into(),
+        source: include_str!("../shaders/fallback.frag").into(),
+    };
+
+    let defines = Defines::new();
+    let mut compile = |source, kind| compile_nice(compiler, cache, source, kind, &defines, &root);
+    let vertex = compile(&vertex, ShaderKind::Vertex);
+    let procedural_vertex = compile(&procedural_vertex, ShaderKind::Vertex);
+    let fragment = compile(&fragment, ShaderKind::Fragment);
+    match (vertex.artefact, procedural_vertex.artefact, fragment.artefact) {
+        (Ok(vertex), Ok(procedural_vertex), Ok(fragment)) => Ok(FallbackSpv {
+            vertex,
+            procedural_vertex,
+            fragment,
+        }),
+        _ => Err(format_err!("Failed to compile the fallback shader")),
     }
+}
+
+/// Result of compiling a single source file
+#[derive(Clone)]
+struct Compiled {
+    /// SPIR-V, either freshly compiled or from the cache
+    artefact: std::result::Result<Vec<u8>, Vec<Diagnostic>>,
+    /// Recorded even if compilation failed, so that fixing an include triggers a recompile
+    includes: Includes,
+}
+
+/// Directory searched for `#include`s
+fn shader_root() -> PathBuf {
+    std::env::var_os(SHADER_ROOT_VAR)
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SHADER_ROOT))
+}
+
+/// Places an include is looked for, in order; `"quoted"` includes are first looked for next to
+/// the including file, then in the shader root, and `<bracketed>` includes only in the shader root
+fn include_candidates(
+    requested: &str,
+    ty: IncludeType,
+    requesting: &str,
+    root: &Path,
+) -> Vec<PathBuf> {
+    let relative = match ty {
+        IncludeType::Relative => Path::new(requesting).parent().map(|dir| dir.join(requested)),
+        IncludeType::Standard => None,
+    };
+
+    relative.into_iter().chain(std::iter::once(root.join(requested))).collect()
+}
+
+/// Canonical form of a path which may not exist yet, so that it matches the watcher's paths once
+/// it's created
+fn canonical_candidate(path: &Path) -> Option<PathBuf> {
+    path.canonicalize().ok().or_else(|| {
+        let dir = path.parent()?;
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        Some(dir.canonicalize().ok()?.join(path.file_name()?))
+    })
+}
+
+/// Path of the file an include refers to, if any
+pub fn find_include(
+    requested: &str,
+    ty: IncludeType,
+    requesting: &str,
+    root: &Path,
+) -> Option<PathBuf> {
+    include_candidates(requested, ty, requesting, root)
+        .into_iter()
+        .find(|p| p.is_file())
+}
+
+/// Find and read an included file
+fn resolve_include(
+    requested: &str,
+    ty: IncludeType,
+    requesting: &str,
+    root: &Path,
+) -> Result<ResolvedInclude, String> {
+    let path = find_include(requested, ty, requesting, root)
+        .ok_or_else(|| {
+            format!(
+                "Cannot find include \"{}\" from \"{}\" (shader root is \"{}\")",
+                requested,
+                requesting,
+                root.display()
+            )
+        })?;
+
+    let content = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read include \"{}\"; {}", path.display(), e))?;
+
+    Ok(ResolvedInclude {
+        resolved_name: path.to_string_lossy().into_owned(),
+        content,
+    })
+}
 
-    Ok(())
+fn print_diagnostics(heading: &str, diagnostics: &[Diagnostic]) {
+    let list = diagnostics
+        .iter()
+        .map(|d| format!("\t{}", d))
+        .collect::<Vec<_>>()
+        .join("\n");
+    console_print(&format!("{}:\n{}", heading, list));
 }
 
-fn compile_nice(compiler: &mut Compiler, path: &str, kind: ShaderKind) -> Option<CompilationArtifact> {
-    let src = match std::fs::read_to_string(path) {
-        Ok(s) => s,
+/// Check a compiled stage against the engine's pipeline layout
+fn check_interface(
+    source: &ShaderSource,
+    spv: &[u8],
+    stage: ShaderStageFlags,
+    engine: &RenderEngine,
+) -> Vec<Diagnostic> {
+    reflection::check_interface(spv, stage, &engine.interface())
+        .into_iter()
+        .map(|message| Diagnostic {
+            file: source.name().to_string(),
+            line: None,
+            message,
+        })
+        .collect()
+}
+
+fn compile_nice(
+    compiler: &mut Compiler,
+    cache: &SpirvCache,
+    source: &ShaderSource,
+    kind: ShaderKind,
+    defines: &Defines,
+    root: &Path,
+) -> Compiled {
+    let heading = format!("Failed to compile shader \"{}\"", source.name());
+    let src = match source.load() {
+        Ok(src) => src,
         Err(e) => {
-            eprintln!("Failed to load shader source at path \"{}\"; {}", path, e);
-            return None;
+            let diagnostics = vec![Diagnostic {
+                file: source.name().to_string(),
+                line: None,
+                message: format!("Failed to load shader source; {}", e),
+            }];
+            print_diagnostics(&heading, &diagnostics);
+            return Compiled {
+                artefact: Err(diagnostics),
+                includes: Includes::new(),
+            };
         }
     };
 
-    let res = compiler.compile_into_spirv(&src, kind, path, "main", None);
-    match res {
-        Ok(art) => Some(art),
-        Err(e) => {
-            eprintln!("Failed to compile shader at path \"{}\"; {}", path, e);
-            None
+    // Skip compilation if neither the source nor its includes have changed since last time
+    let key = CacheKey::new(source.name(), &src, kind, defines, root);
+    if let Some((spv, includes)) = cache.load(key, root) {
+        return Compiled {
+            artefact: Ok(spv),
+            includes,
+        };
+    }
+
+    let found = RefCell::new(FoundIncludes::default());
+    let artefact = compile_with_includes(compiler, source.name(), &src, kind, defines, root, &found)
+        .map(|art| art.as_binary_u8().to_vec());
+    let FoundIncludes { files: includes, records } = found.into_inner();
+
+    match &artefact {
+        Ok(spv) => {
+            if let Err(e) = cache.store(key, spv, &records) {
+                console_print(&format!("Failed to cache shader \"{}\"; {}", source.name(), e));
+            }
+        }
+        Err(diagnostics) => print_diagnostics(&heading, diagnostics),
+    }
+
+    Compiled { artefact, includes }
+}
+
+/// Includes seen by the include callback while compiling
+#[derive(Default)]
+struct FoundIncludes {
+    /// Files the shader depends on, including everywhere a missing include could appear
+    files: Includes,
+    /// How each include that was found was resolved, for the cache
+    records: Vec<IncludeRecord>,
+}
+
+fn compile_with_includes(
+    compiler: &mut Compiler,
+    path: &str,
+    src: &str,
+    kind: ShaderKind,
+    defines: &Defines,
+    root: &Path,
+    found: &RefCell<FoundIncludes>,
+) -> std::result::Result<CompilationArtifact, Vec<Diagnostic>> {
+    let mut options = CompileOptions::new().ok_or_else(|| {
+        vec![Diagnostic {
+            file: path.to_string(),
+            line: None,
+            message: "Failed to create compile options".into(),
+        }]
+    })?;
+    for (name, value) in defines {
+        options.add_macro_definition(name, value.as_deref());
+    }
+    options.set_include_callback(|requested, ty, requesting, _depth| {
+        match resolve_include(requested, ty, requesting, root) {
+            Ok(resolved) => {
+                if let Ok(path) = Path::new(&resolved.resolved_name).canonicalize() {
+                    let mut found = found.borrow_mut();
+                    found.files.insert(path.clone());
+                    found.records.push(IncludeRecord {
+                        requested: requested.to_string(),
+                        standard: matches!(ty, IncludeType::Standard),
+                        requesting: requesting.to_string(),
+                        resolved: path,
+                    });
+                }
+                Ok(resolved)
+            }
+            Err(e) => {
+                // Depend on everywhere the include could appear, so that creating it recompiles
+                let candidates = include_candidates(requested, ty, requesting, root);
+                found
+                    .borrow_mut()
+                    .files
+                    .extend(candidates.iter().filter_map(|p| canonical_candidate(p)));
+                Err(e)
+            }
         }
+    });
+
+    compiler
+        .compile_into_spirv(src, kind, path, "main", Some(&options))
+        .map_err(|e| match e {
+            shaderc::Error::CompilationError(_, log) => parse_diagnostics(path, &log),
+            other => vec![Diagnostic {
+                file: path.to_string(),
+                line: None,
+                message: other.to_string(),
+            }],
+        })
+}
+
+/// Split shaderc's error log, made of lines like `file:12: error: message`, into diagnostics.
+/// Lines without a location are attributed to the file being compiled, except for the summary
+/// (`2 errors generated.`) at the end.
+fn parse_diagnostics(path: &str, log: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    for line in log.lines().map(str::trim).filter(|l| !l.is_empty() && !is_summary(l)) {
+        // File names may themselves contain colons, so look for the severity first
+        let severity = [": error: ", ": warning: "]
+            .iter()
+            .filter_map(|marker| line.find(marker))
+            .min();
+
+        let diagnostic = match severity {
+            Some(idx) => {
+                let (location, message) = (&line[..idx], &line[idx + 2..]);
+                let (file, line) = match location.rfind(':') {
+                    Some(colon) => match location[colon + 1..].parse() {
+                        Ok(n) => (&location[..colon], Some(n)),
+                        Err(_) => (location, None),
+                    },
+                    None => (location, None),
+                };
+                Diagnostic {
+                    file: file.to_string(),
+                    line,
+                    message: message.to_string(),
+                }
+            }
+            None => Diagnostic {
+                file: path.to_string(),
+                line: None,
+                message: line.to_string(),
+            },
+        };
+        diagnostics.push(diagnostic);
+    }
+    diagnostics
+}
+
+/// Whether a log line is the count of errors and warnings, e.g. `1 warning and 2 errors generated.`
+fn is_summary(line: &str) -> bool {
+    let starts_with_count = line
+        .split(' ')
+        .next()
+        .map_or(false, |n| n.parse::<u32>().is_ok());
+    starts_with_count && line.ends_with(" generated.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shaderc_log() {
+        let log = "shaders/unlit.frag:10: error: 'colour' : undeclared identifier\n\
+                   shaders/unlit.frag:10: error: '' : compilation terminated \n\
+                   2 errors generated.\n";
+        let diagnostics = parse_diagnostics("shaders/unlit.frag", log);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].file, "shaders/unlit.frag");
+        assert_eq!(diagnostics[0].line, Some(10));
+        assert_eq!(diagnostics[0].message, "error: 'colour' : undeclared identifier");
+        assert_eq!(diagnostics[1].message, "error: '' : compilation terminated");
+    }
+
+    #[test]
+    fn parse_inline_shader_log() {
+        // Inline shaders are named after their call site, which contains a colon
+        let log = "programs/fx.lua:12 (vertex):3: warning: '#extension' : extension not supported\n\
+                   programs/fx.lua:12 (vertex):7: error: 'vec' : no matching overloaded function found\n\
+                   1 warning and 1 error generated.\n";
+        let diagnostics = parse_diagnostics("programs/fx.lua:12 (vertex)", log);
+
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].file, "programs/fx.lua:12 (vertex)");
+        assert_eq!(diagnostics[0].line, Some(3));
+        assert_eq!(diagnostics[1].line, Some(7));
+    }
+
+    #[test]
+    fn unlocated_lines_belong_to_the_shader() {
+        let diagnostics = parse_diagnostics("a.vert", "Something went wrong\n1 error generated.");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].file, "a.vert");
+        assert_eq!(diagnostics[0].line, None);
     }
 }
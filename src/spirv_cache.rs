@@ -0,0 +1,171 @@
+use crate::shader_update_calc::{find_include, Defines, Includes};
+use shaderc::{IncludeType, ShaderKind};
+use std::path::{Path, PathBuf};
+
+/// Directory holding compiled shaders, relative to the working directory
+pub const CACHE_DIR: &str = ".shader_cache";
+
+/// Change this to invalidate every cache entry, e.g. when compile options change
+const CACHE_VERSION: u64 = 2;
+
+/// FNV-1a; unlike std's hasher, it is stable between runs and compiler versions
+struct Fnv(u64);
+
+impl Fnv {
+    fn new() -> Self {
+        Fnv(0xcbf29ce484222325)
+    }
+
+    /// Hash some bytes, followed by a separator so that adjacent fields can't run together
+    fn field(&mut self, bytes: &[u8]) -> &mut Self {
+        for &b in bytes.iter().chain(&(bytes.len() as u64).to_le_bytes()) {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+        self
+    }
+}
+
+fn hash_bytes(bytes: &[u8]) -> u64 {
+    Fnv::new().field(bytes).0
+}
+
+/// Identifies a single compilation; the includes aren't known until the source is preprocessed,
+/// so they're checked on load instead
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CacheKey(u64);
+
+impl CacheKey {
+    /// The name matters as well as the source text, as relative includes are found next to it
+    pub fn new(
+        name: &str,
+        source: &str,
+        kind: ShaderKind,
+        defines: &Defines,
+        shader_root: &Path,
+    ) -> Self {
+        let mut hasher = Fnv::new();
+        hasher
+            .field(&CACHE_VERSION.to_le_bytes())
+            .field(name.as_bytes())
+            .field(source.as_bytes())
+            .field(format!("{:?}", kind).as_bytes())
+            .field(shader_root.to_string_lossy().as_bytes());
+        for (name, value) in defines {
+            hasher.field(name.as_bytes());
+            match value {
+                Some(value) => hasher.field(b"=").field(value.as_bytes()),
+                None => hasher.field(b""),
+            };
+        }
+        CacheKey(hasher.0)
+    }
+}
+
+/// How an include was found, so that the search can be repeated when the cache is checked
+#[derive(Clone, Debug)]
+pub struct IncludeRecord {
+    /// Name given to `#include`
+    pub requested: String,
+    /// `<bracketed>` rather than `"quoted"`
+    pub standard: bool,
+    /// Name of the including file, as given to the include callback
+    pub requesting: String,
+    /// Canonical path the include resolved to
+    pub resolved: PathBuf,
+}
+
+/// Compiled SPIR-V kept on disk between runs. Each entry is stored as `<key>.spv`, along with
+/// `<key>.deps` describing every include. An entry is only used if each include still resolves
+/// to the same file, for instance not to a newly created one earlier in the search order, and
+/// that file is unchanged.
+pub struct SpirvCache {
+    dir: PathBuf,
+}
+
+impl Default for SpirvCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpirvCache {
+    pub fn new() -> Self {
+        Self {
+            dir: PathBuf::from(CACHE_DIR),
+        }
+    }
+
+    fn paths(&self, key: CacheKey) -> (PathBuf, PathBuf) {
+        let stem = format!("{:016x}", key.0);
+        (
+            self.dir.join(format!("{}.spv", stem)),
+            self.dir.join(format!("{}.deps", stem)),
+        )
+    }
+
+    /// Find the SPIR-V and includes of an earlier compilation, if none of the includes changed
+    pub fn load(&self, key: CacheKey, shader_root: &Path) -> Option<(Vec<u8>, Includes)> {
+        let (spv_path, deps_path) = self.paths(key);
+        let deps = std::fs::read_to_string(deps_path).ok()?;
+
+        let mut includes = Includes::new();
+        for line in deps.lines() {
+            // Fields are separated by tabs, as names may contain spaces
+            let mut parts = line.split('\t');
+            let hash = u64::from_str_radix(parts.next()?, 16).ok()?;
+            let ty = match parts.next()? {
+                "standard" => IncludeType::Standard,
+                _ => IncludeType::Relative,
+            };
+            let requested = parts.next()?;
+            let requesting = parts.next()?;
+            let resolved = PathBuf::from(parts.next()?);
+
+            let found = find_include(requested, ty, requesting, shader_root)?;
+            if found.canonicalize().ok()? != resolved {
+                return None;
+            }
+            if hash_bytes(&std::fs::read(&resolved).ok()?) != hash {
+                return None;
+            }
+            includes.insert(resolved);
+        }
+
+        let spv = std::fs::read(spv_path).ok()?;
+        Some((spv, includes))
+    }
+
+    /// Remember the result of a compilation. Includes are hashed as they are now, so this must
+    /// be called right after compiling.
+    pub fn store(
+        &self,
+        key: CacheKey,
+        spv: &[u8],
+        includes: &[IncludeRecord],
+    ) -> std::io::Result<()> {
+        let mut deps = String::new();
+        for include in includes {
+            let hash = hash_bytes(&std::fs::read(&include.resolved)?);
+            let ty = match include.standard {
+                true => "standard",
+                false => "relative",
+            };
+            deps += &format!(
+                "{:016x}\t{}\t{}\t{}\t{}\n",
+                hash,
+                ty,
+                include.requested,
+                include.requesting,
+                include.resolved.display()
+            );
+        }
+
+        std::fs::create_dir_all(&self.dir)?;
+        let (spv_path, deps_path) = self.paths(key);
+        // The deps are written last, so that a partially written entry is never loaded
+        let _ = std::fs::remove_file(&deps_path);
+        std::fs::write(spv_path, spv)?;
+        std::fs::write(deps_path, deps)
+    }
+}
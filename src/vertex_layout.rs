@@ -0,0 +1,184 @@
+use watertender::vk;
+
+/// Component type of a vertex attribute
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub enum AttributeFormat {
+    F32,
+    I32,
+    U32,
+}
+
+impl AttributeFormat {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "f32" | "float" => Some(Self::F32),
+            "i32" | "int" => Some(Self::I32),
+            "u32" | "uint" => Some(Self::U32),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::F32 => "f32",
+            Self::I32 => "i32",
+            Self::U32 => "u32",
+        }
+    }
+}
+
+/// A single named vertex attribute
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct VertexAttribute {
+    pub name: String,
+    /// Number of components, 1 through 4
+    pub components: u32,
+    pub format: AttributeFormat,
+}
+
+/// Layout of interleaved vertex data. Attributes are bound to consecutive locations, starting at 0.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Default)]
+pub struct VertexLayout {
+    pub attributes: Vec<VertexAttribute>,
+}
+
+/// Every component is four bytes wide
+const COMPONENT_SIZE: u32 = 4;
+
+impl VertexLayout {
+    /// Position and color, each three floats; the layout used when none is given
+    pub fn pos_color() -> Self {
+        let attr = |name: &str| VertexAttribute {
+            name: name.into(),
+            components: 3,
+            format: AttributeFormat::F32,
+        };
+        Self {
+            attributes: vec![attr("pos"), attr("color")],
+        }
+    }
+
+    /// Number of values making up each vertex
+    pub fn components_per_vertex(&self) -> usize {
+        self.attributes.iter().map(|a| a.components as usize).sum()
+    }
+
+    /// Size of a vertex in bytes
+    pub fn stride(&self) -> u32 {
+        self.components_per_vertex() as u32 * COMPONENT_SIZE
+    }
+
+    /// This layout's attributes followed by those of `other`
+    pub fn concat(&self, other: &VertexLayout) -> VertexLayout {
+        VertexLayout {
+            attributes: self.attributes.iter().chain(&other.attributes).cloned().collect(),
+        }
+    }
+
+    /// Short description such as `pos: 3 x f32, color: 3 x f32`
+    pub fn describe(&self) -> String {
+        self.attributes
+            .iter()
+            .map(|a| format!("{}: {} x {}", a.name, a.components, a.format.name()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Offset (in values), component count and name of the attribute holding vertex positions;
+    /// the float attribute named `pos` or `position`, or else the first attribute if it is a float
+    pub fn position(&self) -> Option<(usize, usize, &str)> {
+        let is_position = |a: &VertexAttribute| {
+            a.format == AttributeFormat::F32 && (a.name == "pos" || a.name == "position")
+        };
+        let idx = match self.attributes.iter().position(is_position) {
+            Some(idx) => idx,
+            None if self.attributes.first()?.format == AttributeFormat::F32 => 0,
+            None => return None,
+        };
+
+        let offset = self.attributes[..idx].iter().map(|a| a.components as usize).sum();
+        let attr = &self.attributes[idx];
+        Some((offset, attr.components as usize, attr.name.as_str()))
+    }
+
+    /// Pack numbers from Lua into vertex data; the values must have been checked with
+    /// `mesh_validation::validate_mesh` or `mesh_validation::check_integer_attributes` first
+    pub fn pack(&self, values: &[f64]) -> Vec<u8> {
+        let per_vertex = self.components_per_vertex();
+        if per_vertex == 0 {
+            return vec![];
+        }
+
+        let mut bytes = Vec::with_capacity(values.len() * COMPONENT_SIZE as usize);
+        for vertex in values.chunks_exact(per_vertex) {
+            let mut values = vertex.iter();
+            for attr in &self.attributes {
+                for &value in values.by_ref().take(attr.components as usize) {
+                    let word = match attr.format {
+                        AttributeFormat::F32 => (value as f32).to_ne_bytes(),
+                        AttributeFormat::I32 => (value as i32).to_ne_bytes(),
+                        AttributeFormat::U32 => (value as u32).to_ne_bytes(),
+                    };
+                    bytes.extend_from_slice(&word);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Buffer bindings; empty if there are no attributes
+    pub fn binding_descriptions(
+        &self,
+        binding: u32,
+        input_rate: vk::VertexInputRate,
+    ) -> Vec<vk::VertexInputBindingDescriptionBuilder<'static>> {
+        if self.attributes.is_empty() {
+            return vec![];
+        }
+
+        vec![vk::VertexInputBindingDescriptionBuilder::new()
+            .binding(binding)
+            .stride(self.stride())
+            .input_rate(input_rate)]
+    }
+
+    /// Attributes at consecutive locations, beginning with `first_location`
+    pub fn attribute_descriptions(
+        &self,
+        binding: u32,
+        first_location: u32,
+    ) -> Vec<vk::VertexInputAttributeDescriptionBuilder<'static>> {
+        let mut offset = 0;
+        self.attributes
+            .iter()
+            .enumerate()
+            .map(|(location, attr)| {
+                let desc = vk::VertexInputAttributeDescriptionBuilder::new()
+                    .binding(binding)
+                    .location(first_location + location as u32)
+                    .format(vk_format(attr.format, attr.components))
+                    .offset(offset);
+                offset += attr.components * COMPONENT_SIZE;
+                desc
+            })
+            .collect()
+    }
+}
+
+fn vk_format(format: AttributeFormat, components: u32) -> vk::Format {
+    use AttributeFormat::*;
+    match (format, components) {
+        (F32, 1) => vk::Format::R32_SFLOAT,
+        (F32, 2) => vk::Format::R32G32_SFLOAT,
+        (F32, 3) => vk::Format::R32G32B32_SFLOAT,
+        (F32, _) => vk::Format::R32G32B32A32_SFLOAT,
+        (I32, 1) => vk::Format::R32_SINT,
+        (I32, 2) => vk::Format::R32G32_SINT,
+        (I32, 3) => vk::Format::R32G32B32_SINT,
+        (I32, _) => vk::Format::R32G32B32A32_SINT,
+        (U32, 1) => vk::Format::R32_UINT,
+        (U32, 2) => vk::Format::R32G32_UINT,
+        (U32, 3) => vk::Format::R32G32B32_UINT,
+        (U32, _) => vk::Format::R32G32B32A32_UINT,
+    }
+}